@@ -0,0 +1,233 @@
+use rustc_hash::FxHashMap;
+
+use crate::game::{Player, Rules, State, SKIP};
+use crate::key::Key;
+use crate::tree::Tree;
+
+// A real game followed from one team's point of view.
+// Every named player is a separate player of the state (so nothing is grouped), with the two
+// detective claimants as players 0 and 1. The town doesn't know the roles, so they are all left
+// as villagers, which changes nothing in its infostate keys or in the day actions. The mafia knows
+// them, so its state is the real one, with the real detective as player 0.
+pub struct Table {
+    pub names: Vec<String>, // by player number
+    pub mafia: bool, // whether the table is followed for the mafia
+    claimants: [usize; 2], // in the order they were given, which is the order of their checks and reports in commands
+    pub state: State,
+    history: Vec<State>, // for undo
+}
+
+// the first node of every infostate of a team (0 = town, 1 = mafia) in the tree
+pub fn index(tree: &Tree, team: usize) -> FxHashMap<Key, usize> {
+    let mut index = FxHashMap::default();
+    tree.walk(|node, state, _| {
+        if state.is_day() == (team == 0) {
+            index.entry(state.infostate_key()).or_insert(node);
+        }
+    });
+    index
+}
+
+impl Table {
+    // mafias is empty when following the town
+    pub fn new(rules: Rules, names: &[String], claimants: &[String], mafias: &[String]) -> Result<Table, String> {
+        if names.len() != rules.n_players {
+            return Err(format!("The checkpoint is for {} players, got {} names", rules.n_players, names.len()));
+        }
+        let position = |name: &String| names.iter().position(|n| n.eq_ignore_ascii_case(name)).ok_or(format!("{} isn't one of the players", name));
+        if names.iter().enumerate().any(|(i, name)| names[..i].iter().any(|n| n.eq_ignore_ascii_case(name))) {
+            return Err("Player names have to be different".to_string());
+        }
+        if claimants.len() != 2 || claimants[0].eq_ignore_ascii_case(&claimants[1]) {
+            return Err("There have to be two different detective claimants".to_string());
+        }
+        let mut claimants = [position(&claimants[0])?, position(&claimants[1])?];
+        let mafias: Vec<usize> = mafias.iter().map(position).collect::<Result<_, _>>()?;
+        let mafia = !mafias.is_empty();
+        if mafia {
+            if mafias.len() != rules.n_mafias {
+                return Err(format!("The checkpoint is for {} mafias, got {}", rules.n_mafias, mafias.len()));
+            }
+            if claimants.iter().filter(|c| mafias.contains(c)).count() != 1 {
+                return Err("Exactly one of the claimants has to be a mafia".to_string());
+            }
+        }
+
+        // the real detective comes first for the mafia
+        let given = claimants;
+        if mafias.contains(&claimants[0]) {
+            claimants.swap(0, 1);
+        }
+        let order: Vec<usize> = claimants.iter().copied().chain((0..names.len()).filter(|i| !claimants.contains(i))).collect();
+        let state = State {
+            kills: vec![],
+            real_requests: vec![],
+            real_responses: vec![],
+            fake_requests: vec![],
+            fake_responses: vec![],
+            players: order.iter().map(|i| Player { alive: true, mafia: mafias.contains(i), count: 1 }).collect(),
+            rules,
+        };
+        let claimants = given.map(|c| order.iter().position(|&i| i == c).unwrap());
+        Ok(Table { names: order.iter().map(|&i| names[i].clone()).collect(), mafia, claimants, state, history: Vec::new() })
+    }
+
+    // whether the team followed is to move
+    pub fn our_turn(&self) -> bool {
+        self.state.is_day() != self.mafia
+    }
+
+    fn name(&self, player: usize) -> &str {
+        if player == SKIP { "nobody" } else { &self.names[player] }
+    }
+
+    fn player(&self, name: &str) -> Result<usize, String> {
+        self.names.iter().position(|n| n.eq_ignore_ascii_case(name)).ok_or(format!("{} isn't one of the players", name))
+    }
+
+    // what happened between the current state and the next one, as typed in a command
+    fn tokens(&self, next: &State) -> Vec<String> {
+        let s = &self.state;
+        let mut tokens = vec![match *next.kills.last().unwrap() {
+            SKIP => "skip".to_string(),
+            player => self.names[player].clone(),
+        }];
+        for claimant in self.claimants {
+            if s.is_day() {
+                let (before, after) = if claimant == 0 { (&s.real_requests, &next.real_requests) } else { (&s.fake_requests, &next.fake_requests) };
+                if after.len() > before.len() && *after.last().unwrap() != SKIP {
+                    tokens.push(self.names[*after.last().unwrap()].clone());
+                }
+            } else {
+                let (before, after) = if claimant == 0 { (&s.real_responses, &next.real_responses) } else { (&s.fake_responses, &next.fake_responses) };
+                if after.len() > before.len() {
+                    tokens.push(if *after.last().unwrap() { "+" } else { "-" }.to_string());
+                }
+            }
+        }
+        tokens
+    }
+
+    // readable description of a decision of the current team
+    pub fn describe(&self, next: &State) -> String {
+        let s = &self.state;
+        if s.is_day() {
+            let mut parts = vec![format!("vote out {}", self.name(*next.kills.last().unwrap()))];
+            for claimant in self.claimants {
+                let (before, after) = if claimant == 0 { (&s.real_requests, &next.real_requests) } else { (&s.fake_requests, &next.fake_requests) };
+                if after.len() > before.len() && *after.last().unwrap() != SKIP {
+                    parts.push(format!("{} checks {}", self.names[claimant], self.names[*after.last().unwrap()]));
+                }
+            }
+            parts.join(", ")
+        } else {
+            let mut parts = vec![format!("kill {}", self.name(*next.kills.last().unwrap()))];
+            if next.fake_responses.len() > s.fake_responses.len() {
+                let verdict = if *next.fake_responses.last().unwrap() { "mafia" } else { "town" };
+                parts.push(format!("{} reports {} as {}", self.names[1], self.names[*next.fake_requests.last().unwrap()], verdict));
+            }
+            parts.join(", ")
+        }
+    }
+
+    // applies a day (vote and checks) or night (kill and reports) command
+    pub fn play(&mut self, phase: &str, tokens: &[&str]) -> Result<(), String> {
+        if (phase == "day") != self.state.is_day() {
+            return Err(format!("It's {}", if self.state.is_day() { "day" } else { "night" }));
+        }
+        let tokens: Vec<String> = tokens.iter().map(|token| token.to_lowercase()).collect();
+
+        // the town's night is applied by hand, since it depends on the roles
+        let next = if !self.state.is_day() && !self.mafia {
+            self.night(&tokens)?
+        } else {
+            let next = self.state.actions().into_iter()
+                .flat_map(|action| action.transitions)
+                .map(|(next, _)| next)
+                .find(|next| self.tokens(next).iter().map(|token| token.to_lowercase()).eq(tokens.iter().cloned()));
+            next.ok_or_else(|| {
+                let example = self.state.actions().into_iter().next().map(|mut action| action.transitions.swap_remove(0).0);
+                let example = example.map_or(String::new(), |next| format!(" (for example \"{} {}\")", phase, self.tokens(&next).join(" ")));
+                format!("That isn't possible in the solved game{}", example)
+            })?
+        };
+        self.history.push(std::mem::replace(&mut self.state, next));
+        Ok(())
+    }
+
+    fn night(&self, tokens: &[String]) -> Result<State, String> {
+        let mut next = self.state.clone();
+        let killed = self.player(tokens.first().ok_or("Name the player killed at night")?)?;
+        if !next.players[killed].alive {
+            return Err(format!("{} is already dead", self.names[killed]));
+        }
+        next.kills.push(killed);
+        next.players[killed].alive = false;
+
+        // claimants who are still alive report their last check
+        let mut reports = tokens[1..].iter();
+        for claimant in self.claimants {
+            let (requests, responses) = if claimant == 0 { (&next.real_requests, &mut next.real_responses) } else { (&next.fake_requests, &mut next.fake_responses) };
+            if next.players[claimant].alive && requests.last().is_some_and(|&request| request != SKIP) {
+                let report = reports.next().ok_or(format!("{} has to report their check (+ or -)", self.names[claimant]))?;
+                responses.push(match report.as_str() {
+                    "+" | "mafia" => true,
+                    "-" | "town" => false,
+                    _ => return Err(format!("Reports are + (mafia) or - (town), got {}", report)),
+                });
+            }
+        }
+        if reports.next().is_some() {
+            return Err("Too many reports".to_string());
+        }
+        Ok(next)
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.history.pop().map(|state| self.state = state).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // random games typed in as commands, checking that the team's infostates are found in the tree
+    #[test]
+    fn commands_lead_to_infostates_of_the_tree() {
+        let rules = Rules { n_players: 6, n_mafias: 2, skip_first_day: true };
+        let (tree, _) = Tree::build(rules);
+        let names: Vec<String> = ["Ann", "Bob", "Cid", "Dan", "Eve", "Fay"].iter().map(|name| name.to_string()).collect();
+        let claimants = vec!["Cid".to_string(), "Eve".to_string()];
+        let mut rng = StdRng::seed_from_u64(0);
+        for (team, mafias) in [(0, vec![]), (1, vec!["Eve".to_string(), "Ann".to_string()])] {
+            let index = index(&tree, team);
+            for _ in 0..200 {
+                let mut table = Table::new(rules, &names, &claimants, &mafias).unwrap();
+                // the real game, which the town only sees through its commands
+                let truth = Table::new(rules, &names, &claimants, &["Cid".to_string(), "Fay".to_string()]).unwrap();
+                let mut truth = if team == 0 { truth } else { Table::new(rules, &names, &claimants, &mafias).unwrap() };
+                let mut turns = 0;
+                while !truth.state.is_terminal() {
+                    if table.our_turn() && table.state.actions().len() > 1 {
+                        assert!(index.contains_key(&table.state.infostate_key()), "{}", table.state.infostate_key());
+                    }
+                    let mut actions = truth.state.actions();
+                    let action = rng.gen_range(0..actions.len());
+                    let transitions = &mut actions[action].transitions;
+                    let next = transitions.swap_remove(rng.gen_range(0..transitions.len())).0;
+                    let tokens = truth.tokens(&next);
+                    let phase = if truth.state.is_day() { "day" } else { "night" };
+                    let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+                    table.play(phase, &tokens).unwrap();
+                    truth.play(phase, &tokens).unwrap();
+                    turns += 1;
+                }
+                assert!(turns > 0);
+                assert!(table.undo());
+            }
+        }
+    }
+}
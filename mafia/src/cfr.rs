@@ -0,0 +1,253 @@
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::tree::Tree;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Infostate {
+    pub strategy: Vec<f32>, // current strategy
+    pub strategy_sum: Vec<f32>, // average strategy (converges to Nash equilibrium)
+    pub regret_sum: Vec<f32>
+}
+
+impl Infostate {
+    pub fn new(n_actions: usize) -> Infostate {
+        Infostate {
+            strategy: vec![1.0 / n_actions as f32; n_actions],
+            strategy_sum: vec![0.0; n_actions],
+            regret_sum: vec![0.0; n_actions]
+        }
+    }
+}
+
+// how regrets are accumulated and turned into strategies by update_regrets
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    Vanilla,
+    // Predictive CFR+: regret sums are clipped at zero, the next strategy adds the last instantaneous
+    // regrets as a prediction of the next ones, and the average strategy is weighted quadratically
+    PredictiveCfrPlus,
+}
+
+pub fn normalize(regrets: &[f32]) -> Vec<f32> {
+    let mut strategy: Vec<f32> = regrets.iter().map(|&x| if x > 0.0 { x } else { 0.0 }).collect();
+    let sum: f32 = strategy.iter().sum();
+    if sum > 0.0 {
+        strategy = strategy.iter().map(|&x| x / sum).collect();
+    } else {
+        // is this ever called?
+        strategy = vec![1.0 / regrets.len() as f32; regrets.len()];
+    }
+    strategy
+}
+
+// calculate counterfactual frequencies for each state
+// (that is, what is the probability of ending up in this state given our actions)
+// skip(level, infostate, reach, action) excludes an action together with its subtree,
+// and the returned mask tells which nodes are still visited
+fn propagate_frequencies<'a>(tree: &mut Tree, player: usize, strategy: impl Fn(usize) -> &'a [f32],
+                             skip: impl Fn(usize, usize, f32, usize) -> bool) -> Vec<bool> {
+    let mut active = vec![false; tree.len()];
+    active[0] = true;
+    for i in 0..tree.n_levels() {
+        // todo: this can be done in parallel
+        for g in tree.level(i) {
+            let (infostate_idx, matching_nodes) = tree.group(g);
+            let strategy = strategy(infostate_idx);
+            let reach: f32 = matching_nodes.clone().filter(|&node_idx| active[node_idx]).map(|node_idx| tree.frequency[node_idx]).sum();
+            let skipped: Vec<bool> = (0..strategy.len()).map(|a| skip(i, infostate_idx, reach, a)).collect();
+            for node_idx in matching_nodes {
+                if !active[node_idx] {
+                    continue;
+                }
+                for ((action, prob_action), skipped) in tree.actions(node_idx).zip_eq(strategy.iter()).zip_eq(skipped.iter()) {
+                    if *skipped {
+                        continue;
+                    }
+                    let counterfactual_prob = if i % 2 == player { 1.0 } else { *prob_action };
+                    for t in tree.transitions(action) {
+                        let to = tree.targets[t] as usize;
+                        tree.frequency[to] = tree.frequency[node_idx] * counterfactual_prob * tree.probs[t];
+                        active[to] = true;
+                    }
+                }
+            }
+        }
+    }
+    active
+}
+
+// calculate winrate for player if the opponent's strategy is fixed
+pub fn perfect_play(tree: &mut Tree, infostates: &[Infostate], player: usize) -> f32 {
+    let strategies: Vec<Vec<f32>> = infostates.iter().map(|infostate| normalize(&infostate.strategy_sum)).collect();
+
+    propagate_frequencies(tree, player, |i| &strategies[i], |_, _, _, _| false);
+
+    // calculate utilities for each state
+    for i in (0..tree.n_levels()).rev() {
+        // todo: this can be done in parallel
+        for g in tree.level(i) {
+            let (infostate_idx, matching_nodes) = tree.group(g);
+            if i % 2 == player {
+                // choose perfect response for infostate
+                let mut utilities = vec![0f32; strategies[infostate_idx].len()];
+                for node_idx in matching_nodes.clone() {
+                    // node -> actions -> transitions
+                    for (utility, action) in utilities.iter_mut().zip_eq(tree.actions(node_idx)) {
+                        for t in tree.transitions(action) {
+                            *utility += tree.winrate(node_idx, t) * tree.probs[t] * tree.frequency[node_idx];
+                        }
+                    }
+                }
+
+                let mut best_action = 0;
+                for (i, u) in utilities.iter().enumerate() {
+                    if *u > utilities[best_action] {
+                        best_action = i;
+                    }
+                }
+
+                for node_idx in matching_nodes {
+                    let action = tree.actions(node_idx).start + best_action;
+                    tree.equity[node_idx] = tree.transitions(action).map(|t| tree.probs[t] * tree.winrate(node_idx, t)).sum();
+                }
+            } else {
+                // calculate equity for opponent nodes
+                for node_idx in matching_nodes {
+                    tree.equity[node_idx] = expected_equity(tree, node_idx, &strategies[infostate_idx]);
+                }
+            }
+        }
+    }
+
+    tree.equity[0]
+}
+
+fn expected_equity(tree: &Tree, node_idx: usize, strategy: &[f32]) -> f32 {
+    let mut equity = 0.0;
+    for (action, prob_action) in tree.actions(node_idx).zip_eq(strategy.iter()) {
+        for t in tree.transitions(action) {
+            equity += tree.winrate(node_idx, t) * prob_action * tree.probs[t];
+        }
+    }
+    equity
+}
+
+// calculate winrate for the town if both teams follow their average strategies
+pub fn evaluate(tree: &mut Tree, infostates: &[Infostate]) -> f32 {
+    let strategies: Vec<Vec<f32>> = infostates.iter().map(|infostate| normalize(&infostate.strategy_sum)).collect();
+    for i in (0..tree.n_levels()).rev() {
+        for g in tree.level(i) {
+            let (infostate_idx, matching_nodes) = tree.group(g);
+            for node_idx in matching_nodes {
+                tree.equity[node_idx] = expected_equity(tree, node_idx, &strategies[infostate_idx]);
+            }
+        }
+    }
+
+    tree.equity[0] // the game starts with day
+}
+
+// regret-based pruning: subtrees of actions played with probability zero are skipped.
+// For the opponent this is exact, since they contribute nothing to the player's regrets.
+// The player's own actions are skipped while their regret is below -threshold times the reach
+// of the infostate (so that they couldn't recover within ~threshold iterations), leaving their regret
+// unchanged, and every `recheck` iterations nothing is pruned to catch actions that got better.
+// Predictive CFR+ clips regrets at zero (and its predictions only add one iteration's regrets, which
+// are at most the reach), so none of its own actions is ever pruned, only the opponent's.
+#[derive(Clone, Copy, Debug)]
+pub struct Pruning {
+    pub threshold: f32,
+    pub recheck: usize,
+}
+
+// calculates counterfactual regrets for player and updates them (iteration counts from 1)
+pub fn update_regrets(tree: &mut Tree, infostates: &mut [Infostate], player: usize, variant: Variant, iteration: usize, pruning: Option<Pruning>) {
+    let active = propagate_frequencies(tree, player, |i| &infostates[i].strategy, |i, infostate_idx, reach, a| {
+        let infostate = &infostates[infostate_idx];
+        match pruning {
+            Some(_) if i % 2 != player => infostate.strategy[a] == 0.0,
+            Some(Pruning { threshold, recheck }) => !iteration.is_multiple_of(recheck)
+                && infostate.strategy[a] == 0.0
+                && infostate.regret_sum[a] < -threshold * reach,
+            None => false,
+        }
+    });
+
+    // calculate equities bottom-up and update regrets
+    for i in (0..tree.n_levels()).rev() {
+        // todo: this can be done in parallel
+        for g in tree.level(i) {
+            let (infostate_idx, matching_nodes) = tree.group(g);
+            let infostate = &mut infostates[infostate_idx];
+
+            let mut regrets = vec![0f32; infostate.strategy.len()];
+            let mut skipped = vec![false; infostate.strategy.len()];
+
+            for node_idx in matching_nodes.filter(|&node_idx| active[node_idx]) {
+                let mut equity = 0.0;
+                for (k, (regret, (action, prob_action))) in regrets.iter_mut().zip_eq(tree.actions(node_idx).zip_eq(infostate.strategy.iter())).enumerate() {
+                    if !active[tree.targets[tree.action_transitions[action] as usize] as usize] {
+                        skipped[k] = true;
+                        continue;
+                    }
+                    for t in tree.transitions(action) {
+                        let winrate = tree.winrate(node_idx, t);
+                        equity += winrate * prob_action * tree.probs[t];
+                        *regret += winrate * tree.probs[t] * tree.frequency[node_idx];
+                    }
+                }
+                for regret in regrets.iter_mut() {
+                    *regret -= equity * tree.frequency[node_idx];
+                }
+                tree.equity[node_idx] = equity;
+            }
+
+            // regrets of pruned actions are left as they are
+            for (regret, skipped) in regrets.iter_mut().zip_eq(skipped) {
+                if skipped {
+                    *regret = 0.0;
+                }
+            }
+
+            //let infostate_frequency: f32 = matching_nodes.iter().map(|node| nodes[*node].frequency).sum();
+            //let scaling_factor = if infostate_frequency > 1e-6 { 1.0 / infostate_frequency } else { 1e6 };
+
+            // CFR+
+            // regrets = regrets.iter().map(|&x| if x > 0.0 { x } else { 0.0 }).collect();
+
+            // weighting
+            //regrets = regrets.iter().map(|&x| x * f32::ln(1.0 + e as f32)).collect();
+            //regrets = regrets.iter().map(|&x| x * 1.2 as f32).collect();
+
+            if i % 2 == player && variant == Variant::PredictiveCfrPlus {
+                let weight = (iteration * iteration) as f32;
+                infostate.strategy_sum = infostate.strategy_sum.iter()
+                    .zip_eq(infostate.strategy.iter())
+                    .map(|(s, x)| s + weight * x)
+                    .collect();
+
+                infostate.regret_sum = infostate.regret_sum.iter()
+                    .zip_eq(regrets.iter())
+                    .map(|(s, r)| (s + r).max(0.0))
+                    .collect();
+
+                let predicted: Vec<f32> = infostate.regret_sum.iter().zip_eq(regrets.iter()).map(|(s, r)| s + r).collect();
+                infostate.strategy = normalize(&predicted);
+            } else if i % 2 == player {
+                // calculate regrets and add to regret_sum
+                infostate.regret_sum = infostate.regret_sum.iter()
+                    .zip_eq(regrets.iter())
+                    .map(|(s, r)| s + r) // can add a discount factor here
+                    .collect();
+
+                // update strategy and strategy_sum
+                infostate.strategy = normalize(&infostate.regret_sum);
+                infostate.strategy_sum = infostate.strategy_sum.iter()
+                    .zip_eq(infostate.strategy.iter())
+                    .map(|(s, x)| s + x)
+                    .collect();
+            }
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::path::Path;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::cfr::Infostate;
+use crate::deep::DeepCfr;
+use crate::game::Rules;
+use crate::key::Key;
+use crate::mccfr::Mccfr;
+use crate::tree::{Group, Tree};
+
+// Binary checkpoints: a magic number and a format version, followed by sections, each being
+// a 4-byte tag, the length of its payload (u64) and the payload itself. Everything is little-endian,
+// sequences are prefixed with their length, and sections are written and read as a stream.
+
+const MAGIC: [u8; 8] = *b"MAFIACKP";
+pub const VERSION: u32 = 3; // 2: checkpoints start with the config section, 3: Deep CFR keeps its sample memories
+
+pub type Tag = [u8; 4];
+pub const CONFIG: Tag = *b"CONF";
+pub const TREE: Tag = *b"TREE";
+pub const INFOSTATES: Tag = *b"INFS";
+pub const MCCFR: Tag = *b"MCCF";
+pub const DEEP: Tag = *b"DEEP";
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    NotACheckpoint,
+    Version(u32), // written by another version of the format
+    UnexpectedSection { expected: Tag, found: Option<Tag> },
+    Corrupted(Tag), // section payload doesn't match its length
+    Invalid(String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tag = |tag: &Tag| String::from_utf8_lossy(tag).into_owned();
+        match self {
+            CheckpointError::Io(error) => write!(f, "{}", error),
+            CheckpointError::NotACheckpoint => write!(f, "not a checkpoint file"),
+            CheckpointError::Version(version) => write!(f, "checkpoint format version {} is not supported (expected {})", version, VERSION),
+            CheckpointError::UnexpectedSection { expected, found: Some(found) } =>
+                write!(f, "expected section {}, found {}", tag(expected), tag(found)),
+            CheckpointError::UnexpectedSection { expected, found: None } => write!(f, "missing section {}", tag(expected)),
+            CheckpointError::Corrupted(section) => write!(f, "section {} is corrupted", tag(section)),
+            CheckpointError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<io::Error> for CheckpointError {
+    fn from(error: io::Error) -> CheckpointError {
+        CheckpointError::Io(error)
+    }
+}
+
+pub trait Encode {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()>;
+}
+
+pub trait Decode: Sized {
+    fn decode(r: &mut impl Read) -> Result<Self, CheckpointError>;
+}
+
+// writes to a temporary file that replaces the checkpoint only once it is complete,
+// so an interrupted save leaves the previous checkpoint intact
+pub struct Writer {
+    w: BufWriter<File>,
+    path: String,
+    keep: usize, // number of checkpoints to keep, the older ones as path.1, path.2, ...
+}
+
+fn numbered(path: &str, i: usize) -> String {
+    format!("{}.{}", path, i)
+}
+
+fn temporary(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+// shift path.1, path.2, ... and keep the current checkpoint as path.1 (it stays in place until it is replaced)
+fn rotate(path: &str, keep: usize) -> io::Result<()> {
+    if keep <= 1 || !Path::new(path).exists() {
+        return Ok(());
+    }
+    for i in (1..keep - 1).rev() {
+        if Path::new(&numbered(path, i)).exists() {
+            fs::rename(numbered(path, i), numbered(path, i + 1))?;
+        }
+    }
+    let previous = numbered(path, 1);
+    if Path::new(&previous).exists() {
+        fs::remove_file(&previous)?;
+    }
+    fs::hard_link(path, &previous).or_else(|_| fs::copy(path, &previous).map(|_| ()))
+}
+
+impl Writer {
+    pub fn create(path: &str, keep: usize) -> Result<Writer, CheckpointError> {
+        let mut w = BufWriter::new(File::create(temporary(path))?);
+        w.write_all(&MAGIC)?;
+        VERSION.encode(&mut w)?;
+        Ok(Writer { w, path: path.to_string(), keep })
+    }
+
+    // the length is filled in once the payload is written
+    pub fn section<T: Encode + ?Sized>(&mut self, tag: Tag, value: &T) -> Result<(), CheckpointError> {
+        self.w.write_all(&tag)?;
+        let start = self.w.stream_position()?;
+        0u64.encode(&mut self.w)?;
+        value.encode(&mut self.w)?;
+        let end = self.w.stream_position()?;
+        self.w.seek(SeekFrom::Start(start))?;
+        (end - start - 8).encode(&mut self.w)?;
+        self.w.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), CheckpointError> {
+        let file = self.w.into_inner().map_err(|error| error.into_error())?;
+        file.sync_all()?;
+        rotate(&self.path, self.keep)?;
+        fs::rename(temporary(&self.path), &self.path)?;
+        Ok(())
+    }
+}
+
+pub struct Reader {
+    r: BufReader<File>,
+    next: Option<Tag>, // tag of the next section (None at the end of the file)
+}
+
+impl Reader {
+    pub fn open(path: &str) -> Result<Reader, CheckpointError> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        if r.read_exact(&mut magic).is_err() || magic != MAGIC {
+            return Err(CheckpointError::NotACheckpoint);
+        }
+        let version = u32::decode(&mut r)?;
+        if version != VERSION {
+            return Err(CheckpointError::Version(version));
+        }
+        let mut reader = Reader { r, next: None };
+        reader.advance()?;
+        Ok(reader)
+    }
+
+    fn advance(&mut self) -> Result<(), CheckpointError> {
+        let mut tag = [0u8; 4];
+        self.next = match self.r.read_exact(&mut tag) {
+            Ok(()) => Some(tag),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(error) => return Err(error.into()),
+        };
+        Ok(())
+    }
+
+    pub fn peek(&self) -> Option<Tag> {
+        self.next
+    }
+
+    pub fn section<T: Decode>(&mut self, tag: Tag) -> Result<T, CheckpointError> {
+        if self.next != Some(tag) {
+            return Err(CheckpointError::UnexpectedSection { expected: tag, found: self.next });
+        }
+        let len = u64::decode(&mut self.r)?;
+        let mut payload = (&mut self.r).take(len);
+        let value = T::decode(&mut payload).map_err(|error| match error {
+            CheckpointError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof => CheckpointError::Corrupted(tag),
+            error => error,
+        })?;
+        if payload.limit() != 0 {
+            return Err(CheckpointError::Corrupted(tag));
+        }
+        self.advance()?;
+        Ok(value)
+    }
+}
+
+// what a checkpoint was trained for and how far, so that training can be resumed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub rules: Rules,
+    pub solver: String, // name of the solver on the command line
+    pub settings: Vec<(String, String)>, // command line settings of the solver that affect training
+    pub epoch: usize, // number of epochs trained
+    pub exploitability: Option<f32>, // width of the equilibrium range at the last evaluation
+}
+
+pub enum Checkpoint {
+    Tabular(Tree, Vec<Infostate>),
+    Sampled(Mccfr),
+    Deep(DeepCfr),
+}
+
+pub fn load(path: &str) -> Result<(Config, Checkpoint), CheckpointError> {
+    let mut reader = Reader::open(path)?;
+    let config = reader.section(CONFIG)?;
+    let checkpoint = match reader.peek() {
+        Some(MCCFR) => Checkpoint::Sampled(reader.section(MCCFR)?),
+        Some(DEEP) => Checkpoint::Deep(reader.section(DEEP)?),
+        _ => Checkpoint::Tabular(reader.section(TREE)?, reader.section(INFOSTATES)?),
+    };
+    Ok((config, checkpoint))
+}
+
+macro_rules! number {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(r: &mut impl Read) -> Result<$t, CheckpointError> {
+                let mut bytes = [0u8; std::mem::size_of::<$t>()];
+                r.read_exact(&mut bytes)?;
+                Ok(<$t>::from_le_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+number!(u8, u32, u64, f32);
+
+impl Encode for usize {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        (*self as u64).encode(w)
+    }
+}
+
+impl Decode for usize {
+    fn decode(r: &mut impl Read) -> Result<usize, CheckpointError> {
+        Ok(u64::decode(r)? as usize)
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        (*self as u8).encode(w)
+    }
+}
+
+impl Decode for bool {
+    fn decode(r: &mut impl Read) -> Result<bool, CheckpointError> {
+        match u8::decode(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(CheckpointError::Invalid(format!("invalid boolean {}", x))),
+        }
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.len().encode(w)?;
+        w.write_all(self.as_bytes())
+    }
+}
+
+impl Decode for String {
+    fn decode(r: &mut impl Read) -> Result<String, CheckpointError> {
+        let bytes: Vec<u8> = Vec::decode(r)?;
+        String::from_utf8(bytes).map_err(|error| CheckpointError::Invalid(error.to_string()))
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.is_some().encode(w)?;
+        self.iter().try_for_each(|x| x.encode(w))
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(r: &mut impl Read) -> Result<Option<T>, CheckpointError> {
+        Ok(if bool::decode(r)? { Some(T::decode(r)?) } else { None })
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.len().encode(w)?;
+        self.iter().try_for_each(|x| x.encode(w))
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.as_slice().encode(w)
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(r: &mut impl Read) -> Result<Vec<T>, CheckpointError> {
+        let len = usize::decode(r)?;
+        // the length isn't trusted for preallocation, the payload may be truncated
+        let mut result = Vec::with_capacity(len.min(1 << 16));
+        for _ in 0..len {
+            result.push(T::decode(r)?);
+        }
+        Ok(result)
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.0.encode(w)?;
+        self.1.encode(w)
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(r: &mut impl Read) -> Result<(A, B), CheckpointError> {
+        Ok((A::decode(r)?, B::decode(r)?))
+    }
+}
+
+impl Encode for Key {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        (self.as_bytes().len() as u8).encode(w)?;
+        w.write_all(self.as_bytes())
+    }
+}
+
+impl Decode for Key {
+    fn decode(r: &mut impl Read) -> Result<Key, CheckpointError> {
+        let mut bytes = vec![0u8; u8::decode(r)? as usize];
+        r.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|error| CheckpointError::Invalid(error.to_string()))?
+            .parse().map_err(CheckpointError::Invalid)
+    }
+}
+
+impl Encode for Rules {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.n_players.encode(w)?;
+        self.n_mafias.encode(w)?;
+        self.skip_first_day.encode(w)
+    }
+}
+
+impl Decode for Rules {
+    fn decode(r: &mut impl Read) -> Result<Rules, CheckpointError> {
+        Ok(Rules { n_players: usize::decode(r)?, n_mafias: usize::decode(r)?, skip_first_day: bool::decode(r)? })
+    }
+}
+
+impl Encode for Config {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.rules.encode(w)?;
+        self.solver.encode(w)?;
+        self.settings.encode(w)?;
+        self.epoch.encode(w)?;
+        self.exploitability.encode(w)
+    }
+}
+
+impl Decode for Config {
+    fn decode(r: &mut impl Read) -> Result<Config, CheckpointError> {
+        Ok(Config {
+            rules: Rules::decode(r)?,
+            solver: String::decode(r)?,
+            settings: Vec::decode(r)?,
+            epoch: usize::decode(r)?,
+            exploitability: Option::decode(r)?,
+        })
+    }
+}
+
+impl Encode for Infostate {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.strategy.encode(w)?;
+        self.strategy_sum.encode(w)?;
+        self.regret_sum.encode(w)
+    }
+}
+
+impl Decode for Infostate {
+    fn decode(r: &mut impl Read) -> Result<Infostate, CheckpointError> {
+        Ok(Infostate { strategy: Vec::decode(r)?, strategy_sum: Vec::decode(r)?, regret_sum: Vec::decode(r)? })
+    }
+}
+
+impl Encode for Group {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.infostate.encode(w)?;
+        self.start.encode(w)?;
+        self.end.encode(w)
+    }
+}
+
+impl Decode for Group {
+    fn decode(r: &mut impl Read) -> Result<Group, CheckpointError> {
+        Ok(Group { infostate: u32::decode(r)?, start: u32::decode(r)?, end: u32::decode(r)? })
+    }
+}
+
+impl Encode for Tree {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.rules.encode(w)?;
+        self.node_actions.encode(w)?;
+        self.action_transitions.encode(w)?;
+        self.targets.encode(w)?;
+        self.probs.encode(w)?;
+        self.infostate.encode(w)?;
+        self.parent.encode(w)?;
+        self.equity.encode(w)?;
+        self.day.encode(w)?;
+        self.frequency.encode(w)?;
+        self.groups.encode(w)?;
+        self.levels.encode(w)
+    }
+}
+
+impl Decode for Tree {
+    fn decode(r: &mut impl Read) -> Result<Tree, CheckpointError> {
+        Ok(Tree {
+            rules: Rules::decode(r)?,
+            node_actions: Vec::decode(r)?,
+            action_transitions: Vec::decode(r)?,
+            targets: Vec::decode(r)?,
+            probs: Vec::decode(r)?,
+            infostate: Vec::decode(r)?,
+            parent: Vec::decode(r)?,
+            equity: Vec::decode(r)?,
+            day: Vec::decode(r)?,
+            frequency: Vec::decode(r)?,
+            groups: Vec::decode(r)?,
+            levels: Vec::decode(r)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> String {
+        std::env::temp_dir().join(format!("mafia-{}-{}", std::process::id(), name)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn tree_round_trips() {
+        let rules = Rules { n_players: 5, n_mafias: 1, skip_first_day: true };
+        let (tree, infostates) = Tree::build(rules);
+        let config = Config {
+            rules,
+            solver: "cfr".to_string(),
+            settings: vec![("prune".to_string(), "false".to_string())],
+            epoch: 100,
+            exploitability: Some(0.01),
+        };
+        let file = path("round-trip");
+        let mut writer = Writer::create(&file, 1).unwrap();
+        writer.section(CONFIG, &config).unwrap();
+        writer.section(TREE, &tree).unwrap();
+        writer.section(INFOSTATES, &infostates).unwrap();
+        writer.finish().unwrap();
+
+        let (loaded_config, Checkpoint::Tabular(loaded_tree, loaded_infostates)) = load(&file).unwrap() else { panic!("wrong checkpoint type") };
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(config, loaded_config);
+        assert_eq!(serde_json::to_string(&(&tree, &infostates)).unwrap(), serde_json::to_string(&(&loaded_tree, &loaded_infostates)).unwrap());
+    }
+
+    #[test]
+    fn mismatched_files_are_rejected() {
+        let file = path("mismatch");
+        std::fs::write(&file, "{}").unwrap();
+        assert!(matches!(load(&file), Err(CheckpointError::NotACheckpoint)));
+
+        let mut data = MAGIC.to_vec();
+        data.extend((VERSION + 1).to_le_bytes());
+        std::fs::write(&file, &data).unwrap();
+        assert!(matches!(load(&file), Err(CheckpointError::Version(v)) if v == VERSION + 1));
+
+        // a section that claims to be longer than the file
+        data = MAGIC.to_vec();
+        data.extend(VERSION.to_le_bytes());
+        data.extend(CONFIG);
+        data.extend(100u64.to_le_bytes());
+        data.extend([0u8; 10]);
+        std::fs::write(&file, &data).unwrap();
+        assert!(matches!(load(&file), Err(CheckpointError::Corrupted(CONFIG))));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn older_checkpoints_are_kept() {
+        let file = path("rotate");
+        let mut saved = Vec::new();
+        for epoch in 0..4 {
+            let config = Config { rules: Rules::default(), solver: "cfr".to_string(), settings: Vec::new(), epoch, exploitability: None };
+            let mut writer = Writer::create(&file, 3).unwrap();
+            writer.section(CONFIG, &config).unwrap();
+            writer.finish().unwrap();
+            saved.push(fs::read(&file).unwrap());
+        }
+        assert_eq!(fs::read(&file).unwrap(), saved[3]);
+        assert_eq!(fs::read(numbered(&file, 1)).unwrap(), saved[2]);
+        assert_eq!(fs::read(numbered(&file, 2)).unwrap(), saved[1]);
+        assert!(!Path::new(&numbered(&file, 3)).exists());
+        assert!(!Path::new(&temporary(&file)).exists());
+        for name in [file.clone(), numbered(&file, 1), numbered(&file, 2)] {
+            fs::remove_file(name).unwrap();
+        }
+    }
+}
@@ -0,0 +1,576 @@
+use std::io::{self, Read, Write};
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::cfr::{normalize, Infostate};
+use crate::checkpoint::{CheckpointError, Decode, Encode};
+use crate::game::{Rules, State};
+use crate::key::{digit_value, Key};
+use crate::mccfr::{resumed_rng, sample};
+use crate::tree::Tree;
+
+// Deep CFR: the regret and average strategy tables are replaced with small neural networks.
+// Since the number of actions varies between infostates, a network scores one action at a time
+// by the key of the infostate the action leads to (which is the current key plus the action).
+
+const SEGMENTS: usize = 5; // town keys have 5 comma-separated parts, mafia keys have 3
+
+// sizes of the one-hot encoding: (positions, letters, symbols)
+fn encoding(rules: Rules) -> (usize, usize, usize) {
+    let positions = 2 * rules.n_players; // max length of a part (kills include skipped days)
+    let letters = rules.n_mafias.max(2); // named detectives / mafias
+    let symbols = 3 + letters + rules.n_players; // '.', '+', '-', letters and numbers
+    (positions, letters, symbols)
+}
+
+pub fn n_features(rules: Rules) -> usize {
+    let (positions, _, symbols) = encoding(rules);
+    SEGMENTS * positions * symbols
+}
+
+// positions of ones in the one-hot encoding of an infostate key
+pub fn features(rules: Rules, key: &Key) -> Vec<u32> {
+    let (positions, letters, symbols) = encoding(rules);
+    let mut result = Vec::with_capacity(key.as_bytes().len());
+    for (segment, part) in key.as_bytes().split(|&c| c == b',').enumerate() {
+        for (position, &c) in part.iter().enumerate() {
+            let symbol = match c {
+                b'.' => 0,
+                b'+' => 1,
+                b'-' => 2,
+                b'a'..=b'z' => 3 + (c - b'a') as usize,
+                _ => 3 + letters + digit_value(c).unwrap(),
+            };
+            result.push(((segment * positions + position) * symbols + symbol) as u32);
+        }
+    }
+    result
+}
+
+#[derive(Clone)]
+struct Layer {
+    n_in: usize,
+    n_out: usize,
+    w: Vec<f32>, // w[i * n_out + o]
+    b: Vec<f32>,
+}
+
+impl Layer {
+    fn new(n_in: usize, n_out: usize, rng: &mut StdRng) -> Layer {
+        // He initialization
+        let scale = (6.0 / n_in as f32).sqrt();
+        Layer {
+            n_in,
+            n_out,
+            w: (0..n_in * n_out).map(|_| rng.gen_range(-scale..scale)).collect(),
+            b: vec![0.0; n_out],
+        }
+    }
+
+    fn zeros_like(&self) -> Layer {
+        Layer { n_in: self.n_in, n_out: self.n_out, w: vec![0.0; self.w.len()], b: vec![0.0; self.b.len()] }
+    }
+}
+
+// multilayer perceptron with a sparse binary input, ReLU hidden layers and a scalar output
+#[derive(Clone)]
+pub struct Mlp {
+    layers: Vec<Layer>,
+}
+
+pub struct Sample {
+    features: Vec<u32>,
+    target: f32,
+    weight: f32,
+}
+
+impl Mlp {
+    pub fn new(n_inputs: usize, hidden: &[usize], rng: &mut StdRng) -> Mlp {
+        let mut sizes = vec![n_inputs];
+        sizes.extend_from_slice(hidden);
+        sizes.push(1);
+        let mut layers: Vec<Layer> = sizes.iter().tuple_windows().map(|(&n_in, &n_out)| Layer::new(n_in, n_out, rng)).collect();
+        // untrained networks predict zero (that is, a uniform strategy)
+        layers.last_mut().unwrap().w.fill(0.0);
+        Mlp { layers }
+    }
+
+    // outputs of every layer
+    fn activations(&self, x: &[u32]) -> Vec<Vec<f32>> {
+        let mut result: Vec<Vec<f32>> = Vec::with_capacity(self.layers.len());
+        for (k, layer) in self.layers.iter().enumerate() {
+            let mut out = layer.b.clone();
+            if k == 0 {
+                for &i in x {
+                    let row = &layer.w[i as usize * layer.n_out..(i as usize + 1) * layer.n_out];
+                    for (o, w) in out.iter_mut().zip(row) {
+                        *o += w;
+                    }
+                }
+            } else {
+                for (i, &a) in result[k - 1].iter().enumerate() {
+                    if a != 0.0 {
+                        let row = &layer.w[i * layer.n_out..(i + 1) * layer.n_out];
+                        for (o, w) in out.iter_mut().zip(row) {
+                            *o += a * w;
+                        }
+                    }
+                }
+            }
+            if k + 1 < self.layers.len() {
+                for o in out.iter_mut() {
+                    *o = o.max(0.0);
+                }
+            }
+            result.push(out);
+        }
+        result
+    }
+
+    pub fn predict(&self, x: &[u32]) -> f32 {
+        self.activations(x).last().unwrap()[0]
+    }
+
+    // add gradients of the weighted squared error on one sample
+    fn backward(&self, sample: &Sample, scale: f32, grads: &mut [Layer]) {
+        let activations = self.activations(&sample.features);
+        let y = activations.last().unwrap()[0];
+        let mut delta = vec![2.0 * sample.weight * (y - sample.target) * scale];
+
+        for k in (0..self.layers.len()).rev() {
+            let layer = &self.layers[k];
+            let grad = &mut grads[k];
+            for (g, d) in grad.b.iter_mut().zip(delta.iter()) {
+                *g += d;
+            }
+            if k == 0 {
+                for &i in &sample.features {
+                    let row = &mut grad.w[i as usize * layer.n_out..(i as usize + 1) * layer.n_out];
+                    for (g, d) in row.iter_mut().zip(delta.iter()) {
+                        *g += d;
+                    }
+                }
+            } else {
+                let input = &activations[k - 1];
+                let mut next_delta = vec![0f32; layer.n_in];
+                for (i, &a) in input.iter().enumerate() {
+                    if a > 0.0 {
+                        let row = &layer.w[i * layer.n_out..(i + 1) * layer.n_out];
+                        let grad_row = &mut grad.w[i * layer.n_out..(i + 1) * layer.n_out];
+                        for ((g, w), d) in grad_row.iter_mut().zip(row).zip(delta.iter()) {
+                            *g += a * d;
+                            next_delta[i] += w * d;
+                        }
+                    }
+                }
+                delta = next_delta;
+            }
+        }
+    }
+
+    // train the network from scratch on weighted samples with Adam
+    pub fn fit(&mut self, samples: &[Sample], steps: usize, batch_size: usize, learning_rate: f32, rng: &mut StdRng) {
+        let hidden: Vec<usize> = self.layers[1..].iter().map(|layer| layer.n_in).collect();
+        *self = Mlp::new(self.layers[0].n_in, &hidden, rng);
+
+        if samples.is_empty() {
+            return;
+        }
+
+        // weights are normalized so that the learning rate doesn't depend on the iteration
+        let mean_weight = samples.iter().map(|sample| sample.weight).sum::<f32>() / samples.len() as f32;
+
+        let (beta1, beta2, eps) = (0.9f32, 0.999f32, 1e-8f32);
+        let mut m: Vec<Layer> = self.layers.iter().map(Layer::zeros_like).collect();
+        let mut v: Vec<Layer> = self.layers.iter().map(Layer::zeros_like).collect();
+
+        for step in 1..=steps {
+            let mut grads: Vec<Layer> = self.layers.iter().map(Layer::zeros_like).collect();
+            for _ in 0..batch_size {
+                let sample = &samples[rng.gen_range(0..samples.len())];
+                self.backward(sample, 1.0 / (batch_size as f32 * mean_weight), &mut grads);
+            }
+
+            let correction1 = 1.0 - beta1.powi(step as i32);
+            let correction2 = 1.0 - beta2.powi(step as i32);
+            for (((layer, grad), m), v) in self.layers.iter_mut().zip(grads.iter()).zip(m.iter_mut()).zip(v.iter_mut()) {
+                let params = layer.w.iter_mut().chain(layer.b.iter_mut());
+                let grads = grad.w.iter().chain(grad.b.iter());
+                let ms = m.w.iter_mut().chain(m.b.iter_mut());
+                let vs = v.w.iter_mut().chain(v.b.iter_mut());
+                for (((p, g), m), v) in params.zip(grads).zip(ms).zip(vs) {
+                    *m = beta1 * *m + (1.0 - beta1) * g;
+                    *v = beta2 * *v + (1.0 - beta2) * g * g;
+                    *p -= learning_rate * (*m / correction1) / ((*v / correction2).sqrt() + eps);
+                }
+            }
+        }
+    }
+}
+
+// uniform sample of everything added to it
+#[derive(Default)]
+struct Reservoir {
+    samples: Vec<Sample>,
+    seen: usize,
+}
+
+impl Reservoir {
+    fn add(&mut self, sample: Sample, capacity: usize, rng: &mut StdRng) {
+        self.seen += 1;
+        if self.samples.len() < capacity {
+            self.samples.push(sample);
+        } else {
+            let j = rng.gen_range(0..self.seen);
+            if j < capacity {
+                self.samples[j] = sample;
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DeepParams {
+    pub hidden: usize, // width of the two hidden layers
+    pub train_steps: usize,
+    pub batch_size: usize,
+    pub learning_rate: f32,
+    pub memory: usize, // capacity of each reservoir
+}
+
+pub struct DeepCfr {
+    pub rules: Rules,
+    pub params: DeepParams,
+    pub iteration: usize,
+    advantage: Vec<Mlp>, // per team: predicted regret of an action
+    policy: Vec<Mlp>, // per team: average strategy probability of an action
+    advantage_memory: Vec<Reservoir>,
+    strategy_memory: Vec<Reservoir>,
+    rng: StdRng,
+}
+
+impl DeepCfr {
+    pub fn new(rules: Rules, params: DeepParams, seed: u64) -> DeepCfr {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let hidden = [params.hidden, params.hidden];
+        DeepCfr {
+            rules,
+            iteration: 0,
+            advantage: (0..2).map(|_| Mlp::new(n_features(rules), &hidden, &mut rng)).collect(),
+            policy: (0..2).map(|_| Mlp::new(n_features(rules), &hidden, &mut rng)).collect(),
+            advantage_memory: vec![Reservoir::default(), Reservoir::default()],
+            strategy_memory: vec![Reservoir::default(), Reservoir::default()],
+            params,
+            rng,
+        }
+    }
+
+    // a resumed run continues with the stream of its epoch instead of replaying the samples of epoch 0
+    pub fn reseed(&mut self, seed: u64, epoch: usize) {
+        self.rng = resumed_rng(seed, epoch);
+    }
+
+    // one Deep CFR iteration: sample `traversals` games for each team and retrain its advantage network
+    pub fn iterate(&mut self, traversals: usize) {
+        self.iteration += 1;
+        for player in 0..2 {
+            for _ in 0..traversals {
+                self.traverse(&State::initial(self.rules), player);
+            }
+            let DeepParams { train_steps, batch_size, learning_rate, .. } = self.params;
+            self.advantage[player].fit(&self.advantage_memory[player].samples, train_steps, batch_size, learning_rate, &mut self.rng);
+        }
+    }
+
+    fn strategy(net: &Mlp, features: &[Vec<u32>]) -> Vec<f32> {
+        let advantages: Vec<f32> = features.iter().map(|x| net.predict(x)).collect();
+        normalize(&advantages)
+    }
+
+    // external-sampling traversal that stores sampled regrets and strategies instead of accumulating them
+    fn traverse(&mut self, s: &State, player: usize) -> f32 {
+        let team = if s.is_day() { 0 } else { 1 };
+
+        if s.is_terminal() {
+            let equity = s.terminal_equity();
+            return if team == player { equity } else { 1.0 - equity };
+        }
+
+        let actions = s.actions();
+        let features: Vec<Vec<u32>> = actions.iter().map(|action| features(self.rules, &action.infostate)).collect();
+        let strategy = Self::strategy(&self.advantage[team], &features);
+        let weight = self.iteration as f32; // linear CFR weighting
+        let capacity = self.params.memory;
+
+        if team == player {
+            let mut values = vec![0f32; actions.len()];
+            for (value, action) in values.iter_mut().zip(actions.iter()) {
+                let t = sample(&mut self.rng, action.transitions.iter().map(|(_, count)| *count as f32));
+                *value = self.traverse(&action.transitions[t].0, player);
+            }
+
+            let equity: f32 = values.iter().zip(strategy.iter()).map(|(v, p)| v * p).sum();
+
+            for (x, value) in features.into_iter().zip(values.iter()) {
+                let sample = Sample { features: x, target: value - equity, weight };
+                self.advantage_memory[player].add(sample, capacity, &mut self.rng);
+            }
+
+            equity
+        } else {
+            let a = sample(&mut self.rng, strategy.iter().copied());
+            for (x, p) in features.into_iter().zip(strategy.iter()) {
+                let sample = Sample { features: x, target: *p, weight };
+                self.strategy_memory[team].add(sample, capacity, &mut self.rng);
+            }
+
+            let action = &actions[a];
+            let t = sample(&mut self.rng, action.transitions.iter().map(|(_, count)| *count as f32));
+            self.traverse(&action.transitions[t].0, player)
+        }
+    }
+
+    // fit the average strategy networks on everything sampled so far
+    pub fn train_policy(&mut self) {
+        let DeepParams { train_steps, batch_size, learning_rate, .. } = self.params;
+        for team in 0..2 {
+            if !self.strategy_memory[team].samples.is_empty() {
+                self.policy[team].fit(&self.strategy_memory[team].samples, train_steps, batch_size, learning_rate, &mut self.rng);
+            }
+        }
+    }
+
+    // average strategy for every infostate of a fully built tree (as strategy_sum, like in tabular CFR)
+    pub fn tabulate(&self, tree: &Tree, template: &[Infostate]) -> Vec<Infostate> {
+        let mut infostates = template.to_vec();
+        let mut done = vec![false; template.len()];
+        tree.walk(|node, state, actions| {
+            let idx = tree.infostate[node] as usize;
+            if !done[idx] {
+                done[idx] = true;
+                let team = if state.is_day() { 0 } else { 1 };
+                let features: Vec<Vec<u32>> = actions.iter().map(|action| features(self.rules, &action.infostate)).collect();
+                let strategy = Self::strategy(&self.policy[team], &features);
+                infostates[idx].strategy_sum = strategy.clone();
+                infostates[idx].strategy = strategy;
+            }
+        });
+        infostates
+    }
+}
+
+impl Encode for Layer {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.n_in.encode(w)?;
+        self.n_out.encode(w)?;
+        self.w.encode(w)?;
+        self.b.encode(w)
+    }
+}
+
+impl Decode for Layer {
+    fn decode(r: &mut impl Read) -> Result<Layer, CheckpointError> {
+        Ok(Layer { n_in: usize::decode(r)?, n_out: usize::decode(r)?, w: Vec::decode(r)?, b: Vec::decode(r)? })
+    }
+}
+
+impl Encode for Mlp {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.layers.encode(w)
+    }
+}
+
+impl Decode for Mlp {
+    fn decode(r: &mut impl Read) -> Result<Mlp, CheckpointError> {
+        Ok(Mlp { layers: Vec::decode(r)? })
+    }
+}
+
+impl Encode for Sample {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.features.encode(w)?;
+        self.target.encode(w)?;
+        self.weight.encode(w)
+    }
+}
+
+impl Decode for Sample {
+    fn decode(r: &mut impl Read) -> Result<Sample, CheckpointError> {
+        Ok(Sample { features: Vec::decode(r)?, target: f32::decode(r)?, weight: f32::decode(r)? })
+    }
+}
+
+impl Encode for Reservoir {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.samples.encode(w)?;
+        self.seen.encode(w)
+    }
+}
+
+impl Decode for Reservoir {
+    fn decode(r: &mut impl Read) -> Result<Reservoir, CheckpointError> {
+        Ok(Reservoir { samples: Vec::decode(r)?, seen: usize::decode(r)? })
+    }
+}
+
+impl Encode for DeepCfr {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.rules.encode(w)?;
+        let DeepParams { hidden, train_steps, batch_size, learning_rate, memory } = self.params;
+        hidden.encode(w)?;
+        train_steps.encode(w)?;
+        batch_size.encode(w)?;
+        learning_rate.encode(w)?;
+        memory.encode(w)?;
+        self.iteration.encode(w)?;
+        self.advantage.encode(w)?;
+        self.policy.encode(w)?;
+        self.advantage_memory.encode(w)?;
+        self.strategy_memory.encode(w)
+    }
+}
+
+impl Decode for DeepCfr {
+    fn decode(r: &mut impl Read) -> Result<DeepCfr, CheckpointError> {
+        let rules = Rules::decode(r)?;
+        let params = DeepParams {
+            hidden: usize::decode(r)?,
+            train_steps: usize::decode(r)?,
+            batch_size: usize::decode(r)?,
+            learning_rate: f32::decode(r)?,
+            memory: usize::decode(r)?,
+        };
+        Ok(DeepCfr {
+            rules,
+            params,
+            iteration: usize::decode(r)?,
+            advantage: Vec::decode(r)?,
+            policy: Vec::decode(r)?,
+            advantage_memory: Vec::decode(r)?,
+            strategy_memory: Vec::decode(r)?,
+            rng: StdRng::seed_from_u64(0), // replaced by reseed()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfr::perfect_play;
+    use crate::lp::solve_game;
+
+    fn params() -> DeepParams {
+        DeepParams { hidden: 8, train_steps: 10, batch_size: 8, learning_rate: 0.01, memory: 50 }
+    }
+
+    #[test]
+    fn checkpoints_keep_sample_memories() {
+        let rules = Rules { n_players: 4, n_mafias: 1, skip_first_day: true };
+        let mut deep = DeepCfr::new(rules, params(), 0);
+        deep.iterate(20);
+        let mut data = Vec::new();
+        deep.encode(&mut data).unwrap();
+        let loaded = DeepCfr::decode(&mut data.as_slice()).unwrap();
+        for (memory, loaded) in [(&deep.advantage_memory, &loaded.advantage_memory), (&deep.strategy_memory, &loaded.strategy_memory)] {
+            for (reservoir, loaded) in memory.iter().zip_eq(loaded) {
+                assert_eq!(reservoir.seen, loaded.seen);
+                assert_eq!(reservoir.samples.len(), loaded.samples.len());
+                for (sample, loaded) in reservoir.samples.iter().zip(&loaded.samples) {
+                    assert_eq!((&sample.features, sample.target, sample.weight), (&loaded.features, loaded.target, loaded.weight));
+                }
+            }
+        }
+        assert!(deep.advantage_memory[0].seen > 0 && deep.strategy_memory[1].seen > 0);
+    }
+
+    #[test]
+    fn networks_fit_a_regression() {
+        // the target is a sum of per-feature values, with one active feature out of each group of 4
+        let mut rng = StdRng::seed_from_u64(0);
+        let value = |i: u32| (i % 7) as f32 / 7.0 - 0.4;
+        let samples: Vec<Sample> = (0..500).map(|_| {
+            let features: Vec<u32> = (0..3).map(|group| group * 4 + rng.gen_range(0..4)).collect();
+            let target = features.iter().map(|&i| value(i)).sum();
+            Sample { features, target, weight: rng.gen_range(0.5..2.0) }
+        }).collect();
+        let mut net = Mlp::new(12, &[16, 16], &mut rng);
+        net.fit(&samples, 2000, 32, 0.01, &mut rng);
+        let error = samples.iter().map(|sample| (net.predict(&sample.features) - sample.target).powi(2)).sum::<f32>() / samples.len() as f32;
+        let variance = samples.iter().map(|sample| sample.target.powi(2)).sum::<f32>() / samples.len() as f32;
+        assert!(error < 0.01 * variance, "{} vs {}", error, variance);
+    }
+
+    #[test]
+    fn reservoirs_keep_a_uniform_sample() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (n, capacity) = (10000, 100);
+        let mut counts = [0usize; 10]; // kept samples by tenth of the stream
+        for _ in 0..100 {
+            let mut reservoir = Reservoir::default();
+            for i in 0..n {
+                reservoir.add(Sample { features: vec![], target: i as f32, weight: 1.0 }, capacity, &mut rng);
+            }
+            assert_eq!((reservoir.samples.len(), reservoir.seen), (capacity, n));
+            for sample in &reservoir.samples {
+                counts[sample.target as usize * 10 / n] += 1;
+            }
+        }
+        // 1000 samples expected per tenth, with a standard deviation of about 30
+        assert!(counts.iter().all(|&count| (850..1150).contains(&count)), "{:?}", counts);
+    }
+
+    #[test]
+    fn features_are_distinct_inputs() {
+        let rules = Rules { n_players: 6, n_mafias: 2, skip_first_day: true };
+        let (tree, _) = Tree::build(rules);
+        tree.walk(|_, state, actions| {
+            for key in std::iter::once(state.infostate_key()).chain(actions.iter().map(|action| action.infostate.clone())) {
+                let x = features(rules, &key);
+                assert!(x.iter().all(|&i| (i as usize) < n_features(rules)), "{}", key);
+                assert!(x.iter().tuple_windows().all(|(a, b)| a < b), "{}", key);
+            }
+        });
+    }
+
+    // trains for the given epochs and returns the equilibrium range of the average strategy
+    // after each of the epochs in `checks`, checking that it brackets the game value
+    fn train(rules: Rules, params: DeepParams, seed: u64, samples: usize, checks: &[usize]) -> Vec<(f32, f32)> {
+        let (mut tree, infostates) = Tree::build(rules);
+        let (value, _) = solve_game::<f64>(&tree, &infostates).unwrap();
+        let mut deep = DeepCfr::new(rules, params, seed);
+        let mut ranges = Vec::new();
+        for epoch in 1..=*checks.last().unwrap() {
+            deep.iterate(samples);
+            if checks.contains(&epoch) {
+                deep.train_policy();
+                let strategies = deep.tabulate(&tree, &infostates);
+                let (min_winrate, max_winrate) = (perfect_play(&mut tree, &strategies, 1), perfect_play(&mut tree, &strategies, 0));
+                assert!(min_winrate as f64 <= value + 1e-4 && value <= max_winrate as f64 + 1e-4, "epoch {}: ({}, {}) vs {}", epoch, min_winrate, max_winrate, value);
+                ranges.push((min_winrate, max_winrate));
+            }
+        }
+        ranges
+    }
+
+    #[test]
+    fn deep_cfr_narrows_the_range() {
+        // measured widths over seeds 0-7: 0.25-0.48 after 2 epochs, 0.14-0.29 after 30 (0.15 for seed 0)
+        let rules = Rules { n_players: 5, n_mafias: 1, skip_first_day: true };
+        let params = DeepParams { hidden: 32, train_steps: 500, batch_size: 128, learning_rate: 0.003, memory: 100000 };
+        let ranges = train(rules, params, 0, 300, &[2, 15, 30]);
+        let widths: Vec<f32> = ranges.iter().map(|(min_winrate, max_winrate)| max_winrate - min_winrate).collect();
+        assert!(widths[0] > 0.22 && widths[2] < 0.22, "{:?}", ranges);
+    }
+
+    #[test]
+    #[ignore] // takes a few minutes
+    fn deep_cfr_approaches_the_lp_value() {
+        // measured ranges: (0.18, 0.62) after 2 epochs, (0.29, 0.50) after 15 around the value of 0.461
+        let rules = Rules { n_players: 6, n_mafias: 2, skip_first_day: true };
+        let params = DeepParams { hidden: 64, train_steps: 1000, batch_size: 256, learning_rate: 0.001, memory: 200000 };
+        let ranges = train(rules, params, 0, 500, &[2, 15]);
+        let widths: Vec<f32> = ranges.iter().map(|(min_winrate, max_winrate)| max_winrate - min_winrate).collect();
+        assert!(widths[1] < 0.25 && widths[1] < widths[0] / 1.5, "{:?}", ranges);
+    }
+}
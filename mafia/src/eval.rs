@@ -0,0 +1,203 @@
+use itertools::Itertools;
+use rand::rngs::StdRng;
+
+use crate::cfr::{normalize, Infostate};
+use crate::mccfr::sample;
+use crate::tree::Tree;
+
+// Exact evaluation of a fixed strategy profile in f64, independent of the f32 training code.
+// Winrates are kept from the town's perspective.
+
+// town winrate at a terminal node
+pub fn town_winrate(tree: &Tree, node: usize) -> f64 {
+    let equity = tree.equity[node] as f64;
+    if tree.day[node] { equity } else { 1.0 - equity }
+}
+
+fn terminal_winrates(tree: &Tree) -> Vec<f64> {
+    (0..tree.len()).map(|node| if tree.is_terminal(node) { town_winrate(tree, node) } else { 0.0 }).collect()
+}
+
+pub fn strategies(infostates: &[Infostate]) -> Vec<Vec<f64>> {
+    infostates.iter().map(|infostate| normalize(&infostate.strategy_sum).into_iter().map(|p| p as f64).collect()).collect()
+}
+
+// town winrates of all nodes when both teams follow the strategies
+pub fn values(tree: &Tree, strategies: &[Vec<f64>]) -> Vec<f64> {
+    let mut town = terminal_winrates(tree);
+    for i in (0..tree.n_levels()).rev() {
+        for g in tree.level(i) {
+            let (infostate_idx, matching_nodes) = tree.group(g);
+            for node_idx in matching_nodes {
+                town[node_idx] = tree.actions(node_idx).zip_eq(strategies[infostate_idx].iter())
+                    .map(|(action, p)| p * tree.transitions(action).map(|t| tree.probs[t] as f64 * town[tree.targets[t] as usize]).sum::<f64>())
+                    .sum();
+            }
+        }
+    }
+    town
+}
+
+// town winrate when both teams follow the strategies
+pub fn value(tree: &Tree, strategies: &[Vec<f64>]) -> f64 {
+    values(tree, strategies)[0]
+}
+
+// reach of each node by chance and the other team (player 0 = town, 1 = mafia)
+pub fn reach(tree: &Tree, strategies: &[Vec<f64>], player: usize) -> Vec<f64> {
+    let mut reach = vec![0f64; tree.len()];
+    reach[0] = 1.0;
+    for i in 0..tree.n_levels() {
+        for g in tree.level(i) {
+            let (infostate_idx, matching_nodes) = tree.group(g);
+            for node_idx in matching_nodes {
+                for (action, p) in tree.actions(node_idx).zip_eq(strategies[infostate_idx].iter()) {
+                    let p = if i % 2 == player { 1.0 } else { *p };
+                    for t in tree.transitions(action) {
+                        reach[tree.targets[t] as usize] = reach[node_idx] * p * tree.probs[t] as f64;
+                    }
+                }
+            }
+        }
+    }
+    reach
+}
+
+// town winrates of the actions at an infostate, averaged over its nodes weighted by their reach
+// (values of the nodes after the action come from `values`)
+pub fn action_values(tree: &Tree, values: &[f64], reach: &[f64], infostate: usize) -> Vec<f64> {
+    let mut total = Vec::new();
+    let mut total_reach = 0.0;
+    for g in (0..tree.n_levels()).flat_map(|i| tree.level(i)) {
+        let (infostate_idx, matching_nodes) = tree.group(g);
+        if infostate_idx != infostate {
+            continue;
+        }
+        for node_idx in matching_nodes {
+            total.resize(tree.actions(node_idx).len(), 0.0);
+            for (sum, action) in total.iter_mut().zip_eq(tree.actions(node_idx)) {
+                *sum += reach[node_idx] * tree.transitions(action).map(|t| tree.probs[t] as f64 * values[tree.targets[t] as usize]).sum::<f64>();
+            }
+            total_reach += reach[node_idx];
+        }
+    }
+    total.into_iter().map(|sum| if total_reach > 0.0 { sum / total_reach } else { 0.0 }).collect()
+}
+
+// decision in a sampled game: the node, and the positions of the action and its outcome
+pub struct Step {
+    pub node: usize,
+    pub action: usize,
+    pub outcome: usize,
+}
+
+pub struct Game {
+    pub steps: Vec<Step>,
+    pub terminal: usize,
+}
+
+// a game played from the root with both teams sampling from the strategies
+pub fn sample_game(tree: &Tree, strategies: &[Vec<f64>], rng: &mut StdRng) -> Game {
+    let mut steps = Vec::new();
+    let mut node = 0;
+    while !tree.is_terminal(node) {
+        let strategy = &strategies[tree.infostate[node] as usize];
+        let action = sample(rng, strategy.iter().map(|&p| p as f32));
+        let transitions = tree.transitions(tree.actions(node).start + action);
+        let outcome = sample(rng, transitions.clone().map(|t| tree.probs[t]));
+        steps.push(Step { node, action, outcome });
+        node = tree.targets[transitions.start + outcome] as usize;
+    }
+    Game { steps, terminal: node }
+}
+
+pub struct BestResponse {
+    pub value: f64, // town winrate when the player best responds
+    pub actions: Vec<Option<usize>>, // best response action at each infostate of the player
+}
+
+// best response of player (0 = town, 1 = mafia) to the other team's strategies
+pub fn best_response(tree: &Tree, strategies: &[Vec<f64>], player: usize) -> BestResponse {
+    let reach = reach(tree, strategies, player);
+
+    let mut town = terminal_winrates(tree);
+    let mut actions = vec![None; strategies.len()];
+    for i in (0..tree.n_levels()).rev() {
+        for g in tree.level(i) {
+            let (infostate_idx, matching_nodes) = tree.group(g);
+            let action_values = |town: &[f64], node_idx: usize| -> Vec<f64> {
+                tree.actions(node_idx)
+                    .map(|action| tree.transitions(action).map(|t| tree.probs[t] as f64 * town[tree.targets[t] as usize]).sum())
+                    .collect()
+            };
+            if i % 2 == player {
+                // the same action for the whole infostate, maximizing the player's winrate weighted by reach
+                let mut utilities = vec![0f64; strategies[infostate_idx].len()];
+                for node_idx in matching_nodes.clone() {
+                    for (utility, value) in utilities.iter_mut().zip_eq(action_values(&town, node_idx)) {
+                        *utility += reach[node_idx] * if player == 0 { value } else { -value };
+                    }
+                }
+                let best = (0..utilities.len()).fold(0, |best, a| if utilities[a] > utilities[best] { a } else { best });
+                actions[infostate_idx] = Some(best);
+                for node_idx in matching_nodes {
+                    town[node_idx] = action_values(&town, node_idx)[best];
+                }
+            } else {
+                for node_idx in matching_nodes {
+                    town[node_idx] = action_values(&town, node_idx).iter().zip_eq(strategies[infostate_idx].iter()).map(|(v, p)| v * p).sum();
+                }
+            }
+        }
+    }
+
+    BestResponse { value: town[0], actions }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfr::{perfect_play, update_regrets, Variant};
+    use crate::game::Rules;
+    use crate::lp::solve_game;
+
+    #[test]
+    fn best_responses_match_training_evaluation() {
+        let rules = Rules { n_players: 5, n_mafias: 1, skip_first_day: true };
+        let (mut tree, mut infostates) = Tree::build(rules);
+        for e in 0..20 {
+            update_regrets(&mut tree, &mut infostates, 0, Variant::Vanilla, e + 1, None);
+            update_regrets(&mut tree, &mut infostates, 1, Variant::Vanilla, e + 1, None);
+        }
+        let strategies = strategies(&infostates);
+        let (town, mafia) = (best_response(&tree, &strategies, 0), best_response(&tree, &strategies, 1));
+        assert!((town.value - perfect_play(&mut tree, &infostates, 0) as f64).abs() < 1e-4);
+        assert!((mafia.value - perfect_play(&mut tree, &infostates, 1) as f64).abs() < 1e-4);
+        let value = value(&tree, &strategies);
+        assert!(mafia.value <= value && value <= town.value);
+
+        // nobody gains by deviating from an equilibrium
+        let (exact, solution) = solve_game::<f64>(&tree, &infostates).unwrap();
+        let strategies = super::strategies(&solution);
+        for player in 0..2 {
+            assert!((best_response(&tree, &strategies, player).value - exact).abs() < 1e-5);
+        }
+
+        // and no single decision does better than the strategy either
+        // (infostates that the player's own strategy avoids aren't constrained by the equilibrium)
+        let values = values(&tree, &strategies);
+        for player in 0..2 {
+            let (reach, own_reach) = (reach(&tree, &strategies, player), reach(&tree, &strategies, 1 - player));
+            for g in (0..tree.n_levels()).filter(|i| i % 2 == player).flat_map(|i| tree.level(i)) {
+                let (infostate_idx, mut matching_nodes) = tree.group(g);
+                if own_reach[matching_nodes.next().unwrap()] < 1e-6 {
+                    continue;
+                }
+                let town: Vec<f64> = action_values(&tree, &values, &reach, infostate_idx);
+                let own: Vec<f64> = town.iter().map(|v| if player == 0 { *v } else { 1.0 - v }).collect();
+                let expected: f64 = own.iter().zip_eq(strategies[infostate_idx].iter()).map(|(v, p)| v * p).sum();
+                assert!(own.iter().all(|v| *v <= expected + 1e-5));
+            }
+        }
+    }
+}
@@ -0,0 +1,592 @@
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::key::{digit, Key, MAX_NUMBER};
+
+// todo: some assertions
+
+// game parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rules {
+    pub n_players: usize,       // total number of players
+    pub n_mafias: usize,        // total number of mafias
+    pub skip_first_day: bool,   // whether to always skip on the first day
+}
+
+impl Default for Rules {
+    fn default() -> Rules {
+        Rules { n_players: 7, n_mafias: 2, skip_first_day: true }
+    }
+}
+
+// every player number has to be a single symbol in keys
+pub const MAX_PLAYERS: usize = MAX_NUMBER;
+
+impl Rules {
+    // rules with an actual game to play, which the solvers and the keys support
+    pub fn validate(&self) -> Result<(), String> {
+        if self.n_mafias < 1 {
+            return Err("There has to be at least one mafia".to_string());
+        }
+        if self.n_players <= 2 * self.n_mafias {
+            return Err(format!("{} mafias win right away among {} players, there have to be more than twice as many players as mafias", self.n_mafias, self.n_players));
+        }
+        if self.n_players > MAX_PLAYERS {
+            return Err(format!("At most {} players are supported", MAX_PLAYERS));
+        }
+        Ok(())
+    }
+}
+
+//const n_detectives: usize = 1;      // number of real detectives
+//const n_doctors: usize = 0;         // number of real doctors
+//const n_fake_detectives: usize = 1; // number of mafias pretending to be detectives
+//const n_fake_doctors: usize = 0;    // number of mafias pretending to be doctor
+//const n_bosses: usize = 0;
+
+//const mafias_forced: bool = true;   // whether mafias have to kill during night
+//const save_self: bool = false;      // whether the doctor can save themselves
+//const save_twice: bool = false;     // whether doctor can save themselves twice in a row
+//const talk_killed: bool = false;    // whether night killed can communicate before leaving
+//const reveal_day: bool = false;     // whether roles of day kills are revealed
+//const reveal_night: bool = false;   // whether roles of night kills are revealed
+//const split_votes: bool = false;    // whether more than one person can be voted out in a tie
+
+pub const SKIP: usize = usize::MAX;
+
+// struct for storing currently indistinguishable players
+#[derive(Debug, Clone, Copy)]
+pub struct Player {
+    pub alive: bool,
+    pub mafia: bool,
+    pub count: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct State {
+    pub kills: Vec<usize>,
+    pub real_requests: Vec<usize>,
+    pub real_responses: Vec<bool>,
+    pub fake_requests: Vec<usize>,
+    pub fake_responses: Vec<bool>,
+    pub players: Vec<Player>,
+    pub rules: Rules
+}
+
+// an action of the current team, as seen by that team,
+// with all count-weighted outcomes it can lead to
+pub struct Action {
+    pub infostate: Key,
+    pub transitions: Vec<(State, usize)>
+}
+
+impl State {
+    pub fn initial(rules: Rules) -> State {
+        State {
+            kills: vec![],
+            real_requests: vec![],
+            real_responses: vec![],
+            fake_requests: vec![],
+            fake_responses: vec![],
+            rules,
+            players: if rules.n_mafias == 1 {
+                vec![
+                    Player {alive: true, mafia: false, count: 1},
+                    Player {alive: true, mafia: true, count: 1},
+                    Player {alive: true, mafia: false, count: rules.n_players - 2}
+                ]
+            } else {
+                vec![
+                    Player {alive: true, mafia: false, count: 1},
+                    Player {alive: true, mafia: true, count: 1},
+                    Player {alive: true, mafia: true, count: rules.n_mafias - 1},
+                    Player {alive: true, mafia: false, count: rules.n_players - rules.n_mafias - 1}
+                ]
+            }
+        }
+    }
+
+    // convert player id to char for printing
+    fn to_char(x: usize) -> char {
+        if x == SKIP {
+            '.'
+        } else {
+            digit(x)
+        }
+    }
+
+    fn inc(c: char) -> char { char::from_u32(c as u32 + 1).unwrap() }
+
+    fn push_players(key: &mut Key, values: &[usize]) {
+        values.iter().for_each(|x| key.push(Self::to_char(*x)));
+    }
+
+    fn push_responses(key: &mut Key, values: &[bool]) {
+        values.iter().for_each(|x| key.push(if *x {'+'} else {'-'}));
+    }
+
+    pub fn is_day(&self) -> bool {
+        self.kills.len().is_multiple_of(2)
+    }
+
+    pub fn state_key(&self) -> Key {
+        // kills real_requests fake_requests fake_responses
+        // '+' means mafia, '-' means villager
+        let mut s = Key::default();
+
+        Self::push_players(&mut s, &self.kills);
+        s.push(',');
+        Self::push_players(&mut s, &self.real_requests);
+        s.push(',');
+        Self::push_players(&mut s, &self.fake_requests);
+        s.push(',');
+        Self::push_responses(&mut s, &self.fake_responses);
+
+        s
+    }
+
+    pub fn infostate_key_town(&self) -> Key {
+        // ab = detectives
+        // kills requests1 responses1 requests2 responses2
+        // whichever gives lexicographically minimal key
+
+        let permutation = |swap: bool| {
+            let mut s = Key::default();
+
+            let mut m = {
+                let mut array = ['?'; MAX_NUMBER];
+                array[0] = if swap { 'b' } else { 'a' };
+                array[1] = if swap { 'a' } else { 'b' };
+                array
+            };
+
+            let mut next_player = 0;
+
+            let mut f = |x: &usize| {
+                if *x == SKIP {
+                    return '.';
+                }
+                if m[*x] == '?' {
+                    m[*x] = digit(next_player);
+                    next_player += 1;
+                }
+                m[*x]
+            };
+
+            self.kills.iter().for_each(|x| s.push(f(x)));
+            s.push(',');
+
+            let (requests1, responses1, requests2, responses2) = if swap {
+                (&self.real_requests, &self.real_responses, &self.fake_requests, &self.fake_responses)
+            } else {
+                (&self.fake_requests, &self.fake_responses, &self.real_requests, &self.real_responses)
+            };
+
+            requests1.iter().for_each(|x| s.push(f(x)));
+            s.push(',');
+            Self::push_responses(&mut s, responses1);
+            s.push(',');
+            requests2.iter().for_each(|x| s.push(f(x)));
+            s.push(',');
+            Self::push_responses(&mut s, responses2);
+
+            s
+        };
+
+        std::cmp::min(permutation(false), permutation(true))
+    }
+
+    pub fn infostate_key_mafia(&self) -> Key {
+        // a = fake detective
+        // bcd... = mafias
+        // kills requests responses
+        let mut s = Key::default();
+
+        let mut m = {
+            let mut array = ['?'; MAX_NUMBER];
+            array[1] = 'a';
+            array
+        };
+
+        let mut next_mafia = 'b';
+        let mut next_villager = 0;
+
+        let mut f = |x: &usize| {
+            if *x == SKIP {
+                return '.';
+            }
+            if m[*x] == '?' {
+                if self.players[*x].mafia {
+                    m[*x] = next_mafia;
+                    next_mafia = Self::inc(next_mafia);
+                } else {
+                    m[*x] = digit(next_villager);
+                    next_villager += 1;
+                }
+            }
+            m[*x]
+        };
+
+        self.kills.iter().for_each(|x| s.push(f(x)));
+        s.push(',');
+        self.fake_requests.iter().for_each(|x| s.push(f(x)));
+        s.push(',');
+        Self::push_responses(&mut s, &self.fake_responses);
+
+        s
+    }
+
+    pub fn infostate_key(&self) -> Key {
+        if self.is_day() {
+            self.infostate_key_town()
+        } else {
+            self.infostate_key_mafia()
+        }
+    }
+
+    // return a new state where a player was possibly assigned a number
+    // all extra copies are reserved a new number
+    fn touch(&self, player_id: usize) -> State {
+        let mut t = self.clone();
+        if player_id != SKIP {
+            let player = self.players[player_id];
+            if player.count > 1 {
+                t.players.push(Player {
+                    alive: true,
+                    mafia: player.mafia,
+                    count: player.count - 1
+                });
+                t.players[player_id].count = 1;
+            }
+        }
+
+        t
+    }
+
+    // list of people for mafia to kill
+    fn kill_candidates(&self) -> Vec<(usize, usize)> {
+        let mut results = Vec::new();
+
+        for (i, player) in self.players.iter().enumerate() {
+            if player.alive {
+                results.push((i, player.count));
+            }
+        }
+
+        if !self.is_day() && self.alive_mafias() == 1 { // never kill self if last mafia
+            results.retain(|(i, _)| !self.players[*i].mafia);
+        }
+
+        results
+    }
+
+    // list of people to vote out (or skip)
+    fn vote_candidates(&self) -> Vec<(usize, usize)> {
+        // todo: never/always kill if detectives in agreement?
+        // todo: detective reporting more than mafias (including other detective)
+        // todo: maybe skip on all even?
+        if self.alive_total() == 4 || (self.rules.skip_first_day && self.kills.is_empty()) { // always skip on 4
+            return vec![(SKIP, 1)];
+        }
+        let mut results = self.kill_candidates();
+        if self.alive_total() > 3 {
+            results.push((SKIP, 1));
+        }
+        // todo: on 3, if both "detectives" are alive, we need to kill one of them
+        // also kill either detective or their checked mafia
+        results
+    }
+
+    fn check_candidates(&self, detective: usize, requests: &[usize]) -> Vec<(usize, usize)> {
+        // candidate must be alive, not checked, and not self
+        if !self.players[detective].alive || self.alive_total() == 2 { // doesn't matter on 3
+            return vec![(SKIP, 1)]; // skip might mean detective is dead or no valid candidates
+        }
+
+        let mut results = Vec::new();
+
+        for (i, player) in self.players.iter().enumerate() {
+            if player.alive && !requests.contains(&i) && i != detective {
+                results.push((i, player.count));
+            }
+        }
+
+        if results.is_empty() {
+            results.push((SKIP, 1));
+        }
+
+        results
+    }
+
+    pub fn alive_total(&self) -> usize {
+        self.players.iter().map(|player| if player.alive { player.count } else { 0 }).sum::<usize>()
+    }
+
+    pub fn alive_mafias(&self) -> usize {
+        self.players.iter().map(|player| if player.alive && player.mafia { player.count } else { 0 }).sum()
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.alive_total() - if self.is_day() { 0usize } else { 1usize } <= 2 * self.alive_mafias() || self.alive_mafias() == 0
+    }
+
+    // win probability of the team to move in a terminal state
+    pub fn terminal_equity(&self) -> f32 {
+        if (self.alive_mafias() == 0) == self.is_day() { 1.0 } else { 0.0 }
+    }
+
+    // all actions of the current team grouped by their infostate key (in sorted order)
+    // the order is what identifies an action within an infostate, so it has to be deterministic
+    pub fn actions(&self) -> Vec<Action> {
+        let mut raw_actions: Vec<(Key, State, usize)> = Vec::new();
+
+        if self.is_day() {
+            // select vote and checks
+            for (p1, c1) in self.vote_candidates() {
+                let mut s1 = self.touch(p1);
+                s1.kills.push(p1);
+                if p1 != SKIP {
+                    s1.players[p1].alive = false;
+                }
+
+                for (p2, c2) in s1.check_candidates(0, &s1.real_requests) {
+                    let mut s2 = s1.touch(p2);
+                    s2.real_requests.push(p2);
+
+                    for (p3, c3) in s2.check_candidates(1, &s2.fake_requests) {
+                        let mut s3 = s2.touch(p3);
+                        s3.fake_requests.push(p3);
+                        raw_actions.push((s3.infostate_key_town(), s3, c1 * c2 * c3));
+                    }
+                }
+            }
+        } else {
+            // select kill and response
+            for (p1, c1) in self.kill_candidates() {
+                let mut s1 = self.touch(p1);
+                s1.kills.push(p1);
+                s1.players[p1].alive = false;
+
+                // if real detective isn't killed, they should respond
+                let real_request = *s1.real_requests.last().unwrap();
+                if s1.players[0].alive && real_request != SKIP {
+                    // ^ remove to allow report on death
+                    s1.real_responses.push(s1.players[real_request].mafia);
+                }
+
+                // if fake detective isn't killed, they should report
+                let fake_request = *s1.fake_requests.last().unwrap();
+                if s1.players[1].alive && fake_request != SKIP {
+                    // ^ remove to allow report on death
+                    for response in [false, true] {
+                        // todo: can't report more mafias than there are in the game?
+                        let mut s2 = s1.clone();
+                        s2.fake_responses.push(response);
+                        raw_actions.push((s2.infostate_key_mafia(), s2, c1));
+                    }
+                } else {
+                    raw_actions.push((s1.infostate_key_mafia(), s1, c1));
+                }
+            }
+        }
+
+        raw_actions.sort_by(|a, b| a.0.cmp(&b.0));
+
+        raw_actions.into_iter()
+            .group_by(|(infostate, _, _)| infostate.clone())
+            .into_iter()
+            .map(|(infostate, group)| Action {
+                infostate,
+                transitions: group.map(|(_, state, count)| (state, count)).collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::key::digit_value;
+
+    const RULES: [Rules; 4] = [
+        Rules { n_players: 5, n_mafias: 1, skip_first_day: true },
+        Rules { n_players: 7, n_mafias: 2, skip_first_day: true },
+        Rules { n_players: 8, n_mafias: 3, skip_first_day: false },
+        Rules { n_players: 13, n_mafias: 3, skip_first_day: true },
+    ];
+
+    // play uniformly random actions and outcomes for at most `depth` moves
+    fn random_state(rng: &mut StdRng, rules: Rules, depth: usize) -> State {
+        let mut s = State::initial(rules);
+        for _ in 0..depth {
+            if s.is_terminal() {
+                break;
+            }
+            let mut actions = s.actions();
+            let a = rng.gen_range(0..actions.len());
+            let t = rng.gen_range(0..actions[a].transitions.len());
+            s = actions[a].transitions.swap_remove(t).0;
+        }
+        s
+    }
+
+    // the same history with group i renamed to perm[i]
+    fn rename(s: &State, perm: &[usize]) -> State {
+        let f = |x: &usize| if *x == SKIP { SKIP } else { perm[*x] };
+        let mut t = s.clone();
+        for (i, player) in s.players.iter().enumerate() {
+            t.players[perm[i]] = *player;
+        }
+        t.kills = s.kills.iter().map(f).collect();
+        t.real_requests = s.real_requests.iter().map(f).collect();
+        t.fake_requests = s.fake_requests.iter().map(f).collect();
+        t
+    }
+
+    // the same history with the roles of the two detectives exchanged
+    fn swap_detectives(s: &State) -> State {
+        let mut perm: Vec<usize> = (0..s.players.len()).collect();
+        perm.swap(0, 1);
+        let mut t = rename(s, &perm);
+        std::mem::swap(&mut t.real_requests, &mut t.fake_requests);
+        std::mem::swap(&mut t.real_responses, &mut t.fake_responses);
+        t
+    }
+
+    // extend a partial bijection between the players of two histories along a pair of sequences
+    fn extend(mapping: &mut HashMap<usize, usize>, inverse: &mut HashMap<usize, usize>, a: &[usize], b: &[usize]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(&x, &y)| {
+            (x == SKIP) == (y == SKIP)
+                && *mapping.entry(x).or_insert(y) == y
+                && *inverse.entry(y).or_insert(x) == x
+        })
+    }
+
+    // whether the town sees the same history up to renaming villagers and not knowing which detective is real
+    fn town_equivalent(a: &State, b: &State) -> bool {
+        [false, true].into_iter().any(|swap| {
+            let (b_real, b_fake) = if swap { (1, 0) } else { (0, 1) };
+            let (b_real_requests, b_real_responses, b_fake_requests, b_fake_responses) = if swap {
+                (&b.fake_requests, &b.fake_responses, &b.real_requests, &b.real_responses)
+            } else {
+                (&b.real_requests, &b.real_responses, &b.fake_requests, &b.fake_responses)
+            };
+            let mut mapping = HashMap::from([(0, b_real), (1, b_fake)]);
+            let mut inverse = HashMap::from([(b_real, 0), (b_fake, 1)]);
+            extend(&mut mapping, &mut inverse, &a.kills, &b.kills)
+                && extend(&mut mapping, &mut inverse, &a.real_requests, b_real_requests)
+                && extend(&mut mapping, &mut inverse, &a.fake_requests, b_fake_requests)
+                && a.real_responses == *b_real_responses
+                && a.fake_responses == *b_fake_responses
+        })
+    }
+
+    // whether the mafia sees the same history up to renaming players it doesn't tell apart
+    fn mafia_equivalent(a: &State, b: &State) -> bool {
+        let mut mapping = HashMap::from([(1, 1)]);
+        let mut inverse = HashMap::from([(1, 1)]);
+        extend(&mut mapping, &mut inverse, &a.kills, &b.kills)
+            && extend(&mut mapping, &mut inverse, &a.fake_requests, &b.fake_requests)
+            && a.fake_responses == b.fake_responses
+            && mapping.iter().all(|(&x, &y)| x == SKIP || a.players[x].mafia == b.players[y].mafia)
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for (n_players, n_mafias) in [(4, 4), (5, 0), (2, 2), (4, 2), (MAX_PLAYERS + 1, 2)] {
+            assert!(Rules { n_players, n_mafias, skip_first_day: true }.validate().is_err(), "{} {}", n_players, n_mafias);
+        }
+        for rules in RULES {
+            assert!(rules.validate().is_ok());
+            assert!(!State::initial(rules).is_terminal());
+        }
+    }
+
+    #[test]
+    fn keys_are_invariant_under_renaming() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for rules in RULES {
+            for _ in 0..2000 {
+                let depth = rng.gen_range(0..2 * rules.n_players);
+                let s = random_state(&mut rng, rules, depth);
+
+                // numbers assigned to groups as they split are arbitrary (except for the detectives)
+                let mut perm: Vec<usize> = (0..s.players.len()).collect();
+                perm[2..].shuffle(&mut rng);
+                let t = rename(&s, &perm);
+                assert_eq!(s.infostate_key_town(), t.infostate_key_town(), "{} / {}", s.state_key(), t.state_key());
+                assert_eq!(s.infostate_key_mafia(), t.infostate_key_mafia(), "{} / {}", s.state_key(), t.state_key());
+
+                // so is whether a group was split before anything happened to its players
+                let alive: Vec<usize> = (0..s.players.len()).filter(|&i| s.players[i].alive).collect();
+                let t = s.touch(*alive.choose(&mut rng).unwrap());
+                assert_eq!(s.infostate_key_town(), t.infostate_key_town(), "{}", s.state_key());
+                assert_eq!(s.infostate_key_mafia(), t.infostate_key_mafia(), "{}", s.state_key());
+
+                // and the town doesn't know which detective is real
+                let t = swap_detectives(&s);
+                assert_eq!(s.infostate_key_town(), t.infostate_key_town(), "{} / {}", s.state_key(), t.state_key());
+            }
+        }
+    }
+
+    #[test]
+    fn keys_are_readable_for_large_games() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for (n_players, n_mafias) in [(10, 1), (13, 1), (20, 1), (MAX_PLAYERS, 1), (MAX_PLAYERS, (MAX_PLAYERS - 1) / 2)] {
+            let rules = Rules { n_players, n_mafias, skip_first_day: false };
+            assert!(rules.validate().is_ok());
+            for _ in 0..1000 {
+                let s = random_state(&mut rng, rules, 4 * n_players);
+                for key in [s.state_key(), s.infostate_key_town(), s.infostate_key_mafia()] {
+                    let readable = key.to_string();
+                    assert!(readable.chars().all(|c| c.is_ascii_alphanumeric() || ".,+-".contains(c)), "{}", readable);
+                    assert_eq!(readable.parse::<Key>().unwrap(), key);
+                }
+                // every player id gets its own symbol
+                let symbols: Vec<char> = s.state_key().to_string().split(',').take(3).flat_map(|part| part.chars().collect::<Vec<_>>()).collect();
+                let ids: Vec<usize> = s.kills.iter().chain(&s.real_requests).chain(&s.fake_requests).copied().collect();
+                for (&c, &x) in symbols.iter().zip(&ids) {
+                    assert_eq!(digit_value(c as u8), if x == SKIP { None } else { Some(x) });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keys_of_small_games_are_inline() {
+        assert_eq!(std::mem::size_of::<Key>(), 48);
+        let mut rng = StdRng::seed_from_u64(3);
+        let rules = Rules { n_players: 9, n_mafias: 3, skip_first_day: false };
+        for _ in 0..1000 {
+            let s = random_state(&mut rng, rules, 4 * rules.n_players);
+            for key in [s.state_key(), s.infostate_key_town(), s.infostate_key_mafia()] {
+                assert!(matches!(key, Key::Inline { .. }), "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn distinguishable_states_have_different_keys() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for rules in RULES {
+            let mut town: HashMap<Key, State> = HashMap::new();
+            let mut mafia: HashMap<Key, State> = HashMap::new();
+            for _ in 0..20000 {
+                let depth = rng.gen_range(0..2 * rules.n_players);
+                let s = random_state(&mut rng, rules, depth);
+
+                let other = town.entry(s.infostate_key_town()).or_insert_with(|| s.clone());
+                assert!(town_equivalent(&s, other), "{} and {} share a town key", s.state_key(), other.state_key());
+
+                let other = mafia.entry(s.infostate_key_mafia()).or_insert_with(|| s.clone());
+                assert!(mafia_equivalent(&s, other), "{} and {} share a mafia key", s.state_key(), other.state_key());
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// state and infostate keys without allocations for the sizes that matter: keys of games up to ~10 players
+// are stored inline, one byte per symbol of their readable form, and only longer ones on the heap
+const INLINE: usize = 46; // so that a key takes 48 bytes
+
+// players are numbered with base-36 digits (0-9, then A-Z), which keeps every number a single symbol
+// and never clashes with the lowercase letters naming detectives and mafias
+pub const MAX_NUMBER: usize = 36;
+
+pub fn digit(x: usize) -> char {
+    assert!(x < MAX_NUMBER, "player number {} doesn't fit in a single digit", x);
+    std::char::from_digit(x as u32, MAX_NUMBER as u32).unwrap().to_ascii_uppercase()
+}
+
+pub fn digit_value(c: u8) -> Option<usize> {
+    (c as char).to_digit(MAX_NUMBER as u32).filter(|_| !c.is_ascii_lowercase()).map(|x| x as usize)
+}
+
+#[derive(Clone)]
+pub enum Key {
+    Inline { len: u8, bytes: [u8; INLINE] },
+    Heap(Vec<u8>),
+}
+
+impl Default for Key {
+    fn default() -> Key {
+        Key::Inline { len: 0, bytes: [0; INLINE] }
+    }
+}
+
+impl Key {
+    pub fn push(&mut self, c: char) {
+        match self {
+            Key::Inline { len, bytes } if (*len as usize) < INLINE => {
+                bytes[*len as usize] = c as u8;
+                *len += 1;
+            }
+            Key::Inline { .. } => {
+                let mut heap = self.as_bytes().to_vec();
+                heap.push(c as u8);
+                *self = Key::Heap(heap);
+            }
+            Key::Heap(heap) => heap.push(c as u8),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Key::Inline { len, bytes } => &bytes[..*len as usize],
+            Key::Heap(heap) => heap,
+        }
+    }
+}
+
+// compared by their readable form, wherever they are stored
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.as_bytes());
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &c in self.as_bytes() {
+            write!(f, "{}", c as char)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Key, String> {
+        if !s.is_ascii() {
+            return Err(format!("invalid key: {}", s));
+        }
+        let mut key = Key::default();
+        s.chars().for_each(|c| key.push(c));
+        Ok(key)
+    }
+}
+
+// keys are stored in their readable form (they are also keys of JSON maps)
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+
+use crate::cfr::Infostate;
+use crate::tree::Tree;
+
+// Sequence-form linear program of the game, solved with a sparse tableau simplex
+// either in floating point or exactly in rational arithmetic
+
+pub trait Scalar: Clone + Zero + One + PartialOrd + ToPrimitive + fmt::Display
+    + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> + Neg<Output = Self> {
+    fn ratio(numerator: usize, denominator: usize) -> Self;
+    fn is_positive(&self) -> bool;
+    fn is_negative(&self) -> bool;
+    fn negligible(&self) -> bool; // dropped from sparse rows
+}
+
+impl Scalar for f64 {
+    fn ratio(numerator: usize, denominator: usize) -> f64 { numerator as f64 / denominator as f64 }
+    fn is_positive(&self) -> bool { *self > 1e-9 }
+    fn is_negative(&self) -> bool { *self < -1e-9 }
+    fn negligible(&self) -> bool { self.abs() < 1e-12 }
+}
+
+impl Scalar for BigRational {
+    fn ratio(numerator: usize, denominator: usize) -> BigRational {
+        BigRational::new(BigInt::from(numerator), BigInt::from(denominator))
+    }
+    fn is_positive(&self) -> bool { Signed::is_positive(self) }
+    fn is_negative(&self) -> bool { Signed::is_negative(self) }
+    fn negligible(&self) -> bool { self.is_zero() }
+}
+
+#[derive(Debug)]
+pub enum LpError {
+    Infeasible,
+    Unbounded,
+    ImperfectRecall(usize), // infostate reached after different sequences of own actions
+}
+
+impl fmt::Display for LpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LpError::Infeasible => write!(f, "linear program is infeasible"),
+            LpError::Unbounded => write!(f, "linear program is unbounded"),
+            LpError::ImperfectRecall(infostate) => write!(f, "infostate {} doesn't have perfect recall", infostate),
+        }
+    }
+}
+
+type Row<T> = Vec<(u32, T)>; // sorted by column
+
+fn entry<T: Scalar>(row: &Row<T>, column: u32) -> Option<&T> {
+    row.binary_search_by_key(&column, |(c, _)| *c).ok().map(|i| &row[i].1)
+}
+
+// row - factor * other
+fn subtract<T: Scalar>(row: &Row<T>, factor: &T, other: &Row<T>) -> Row<T> {
+    let mut result = Vec::with_capacity(row.len() + other.len());
+    let (mut i, mut j) = (0, 0);
+    while i < row.len() || j < other.len() {
+        if j == other.len() || (i < row.len() && row[i].0 < other[j].0) {
+            result.push(row[i].clone());
+            i += 1;
+        } else if i == row.len() || other[j].0 < row[i].0 {
+            result.push((other[j].0, -(factor.clone() * other[j].1.clone())));
+            j += 1;
+        } else {
+            let x = row[i].1.clone() - factor.clone() * other[j].1.clone();
+            if !x.negligible() {
+                result.push((row[i].0, x));
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    result
+}
+
+// (coefficients, is equality, right-hand side)
+type Constraint<T> = (Vec<(usize, T)>, bool, T);
+
+// maximize objective * x subject to rows (equalities or <=) and x >= 0, with nonnegative right-hand sides
+pub struct Lp<T> {
+    pub n_vars: usize,
+    pub objective: Vec<(usize, T)>,
+    pub rows: Vec<Constraint<T>>,
+}
+
+pub struct LpSolution<T> {
+    pub value: T,
+    pub primal: Vec<T>,
+    pub dual: Vec<T>, // one per row
+}
+
+struct Tableau<T> {
+    rows: Vec<Row<T>>,
+    rhs: Vec<T>,
+    objective: Vec<T>, // reduced costs (the objective row stores -c)
+    value: T,
+    basis: Vec<u32>,
+    blocked: Vec<bool>, // columns that can't enter the basis
+    bland: bool,
+}
+
+impl<T: Scalar> Tableau<T> {
+    fn pivot(&mut self, r: usize, c: u32) {
+        let p = entry(&self.rows[r], c).unwrap().clone();
+        let pivot_row: Row<T> = self.rows[r].iter().map(|(j, x)| (*j, x.clone() / p.clone())).collect();
+        let pivot_rhs = self.rhs[r].clone() / p;
+
+        for i in 0..self.rows.len() {
+            if i != r {
+                if let Some(a) = entry(&self.rows[i], c).cloned() {
+                    self.rows[i] = subtract(&self.rows[i], &a, &pivot_row);
+                    self.rhs[i] = self.rhs[i].clone() - a * pivot_rhs.clone();
+                }
+            }
+        }
+
+        let a = self.objective[c as usize].clone();
+        if !a.is_zero() {
+            for (j, x) in &pivot_row {
+                self.objective[*j as usize] = self.objective[*j as usize].clone() - a.clone() * x.clone();
+            }
+            self.value = self.value.clone() - a * pivot_rhs.clone();
+        }
+
+        self.rows[r] = pivot_row;
+        self.rhs[r] = pivot_rhs;
+        self.basis[r] = c;
+    }
+
+    // run simplex iterations until the objective row has no negative reduced costs
+    fn optimize(&mut self) -> Result<(), LpError> {
+        let mut degenerate = 0;
+        loop {
+            // entering column: most negative reduced cost (Bland's rule after a long degenerate streak)
+            let mut entering: Option<usize> = None;
+            for (j, x) in self.objective.iter().enumerate() {
+                if !self.blocked[j] && x.is_negative() {
+                    if self.bland {
+                        entering = Some(j);
+                        break;
+                    }
+                    if entering.is_none_or(|e| *x < self.objective[e]) {
+                        entering = Some(j);
+                    }
+                }
+            }
+            let Some(c) = entering else { return Ok(()) };
+
+            // leaving row: minimum ratio, ties broken by the smallest basic variable
+            let mut leaving: Option<(usize, T)> = None;
+            for (i, row) in self.rows.iter().enumerate() {
+                if let Some(a) = entry(row, c as u32) {
+                    if a.is_positive() {
+                        let ratio = self.rhs[i].clone() / a.clone();
+                        let better = match &leaving {
+                            None => true,
+                            Some((l, best)) => ratio < *best || (ratio == *best && self.basis[i] < self.basis[*l]),
+                        };
+                        if better {
+                            leaving = Some((i, ratio));
+                        }
+                    }
+                }
+            }
+            let Some((r, ratio)) = leaving else { return Err(LpError::Unbounded) };
+
+            if ratio.negligible() {
+                degenerate += 1;
+                if degenerate > 1000 {
+                    self.bland = true;
+                }
+            } else {
+                degenerate = 0;
+            }
+
+            self.pivot(r, c as u32);
+        }
+    }
+}
+
+impl<T: Scalar> Lp<T> {
+    // two-phase simplex: slacks for inequalities, artificial variables for equalities
+    pub fn solve(&self) -> Result<LpSolution<T>, LpError> {
+        let m = self.rows.len();
+        let mut n_cols = self.n_vars;
+        let mut extra = Vec::with_capacity(m); // slack or artificial column of each row
+        for _ in 0..m {
+            extra.push(n_cols as u32);
+            n_cols += 1;
+        }
+
+        let mut tableau = Tableau {
+            rows: Vec::with_capacity(m),
+            rhs: Vec::with_capacity(m),
+            objective: vec![T::zero(); n_cols],
+            value: T::zero(),
+            basis: extra.clone(),
+            blocked: vec![false; n_cols],
+            bland: false,
+        };
+
+        for (i, (coefficients, _, rhs)) in self.rows.iter().enumerate() {
+            let mut row: Row<T> = coefficients.iter().map(|(j, x)| (*j as u32, x.clone())).collect();
+            row.sort_by_key(|(j, _)| *j);
+            row.push((extra[i], T::one()));
+            tableau.rows.push(row);
+            tableau.rhs.push(rhs.clone());
+        }
+
+        // phase 1: maximize minus the sum of artificial variables
+        for (i, (_, equality, _)) in self.rows.iter().enumerate() {
+            if *equality {
+                tableau.objective[extra[i] as usize] = T::one();
+                let row = tableau.rows[i].clone();
+                for (j, x) in &row {
+                    tableau.objective[*j as usize] = tableau.objective[*j as usize].clone() - x.clone();
+                }
+                tableau.value = tableau.value.clone() - tableau.rhs[i].clone();
+            }
+        }
+        tableau.optimize()?;
+        if tableau.value.is_negative() {
+            return Err(LpError::Infeasible);
+        }
+
+        // artificial variables left in the basis are zero and can be pivoted out (or their rows are redundant)
+        for (i, (_, equality, _)) in self.rows.iter().enumerate() {
+            if *equality {
+                tableau.blocked[extra[i] as usize] = true;
+            }
+        }
+        for r in 0..m {
+            if tableau.blocked[tableau.basis[r] as usize] {
+                if let Some(&(c, _)) = tableau.rows[r].iter().find(|(c, _)| !tableau.blocked[*c as usize]) {
+                    tableau.pivot(r, c);
+                }
+            }
+        }
+
+        // phase 2
+        tableau.objective = vec![T::zero(); n_cols];
+        tableau.value = T::zero();
+        tableau.bland = false;
+        for (j, x) in &self.objective {
+            tableau.objective[*j] = -x.clone();
+        }
+        for r in 0..m {
+            let a = tableau.objective[tableau.basis[r] as usize].clone();
+            if !a.is_zero() {
+                let row = tableau.rows[r].clone();
+                for (j, x) in &row {
+                    tableau.objective[*j as usize] = tableau.objective[*j as usize].clone() - a.clone() * x.clone();
+                }
+                tableau.value = tableau.value.clone() - a * tableau.rhs[r].clone();
+            }
+        }
+        tableau.optimize()?;
+
+        let mut primal = vec![T::zero(); self.n_vars];
+        for (r, &j) in tableau.basis.iter().enumerate() {
+            if (j as usize) < self.n_vars {
+                primal[j as usize] = tableau.rhs[r].clone();
+            }
+        }
+        let dual = extra.iter().map(|&j| tableau.objective[j as usize].clone()).collect();
+
+        Ok(LpSolution { value: tableau.value, primal, dual })
+    }
+}
+
+// solve the game exactly: returns the town's winrate and an equilibrium (as strategy_sum of each infostate)
+pub fn solve_game<T: Scalar>(tree: &Tree, template: &[Infostate]) -> Result<(T, Vec<Infostate>), LpError> {
+    // sequences of each team are numbered from 1 (0 is the empty sequence)
+    let mut next_sequence = [1usize, 1usize];
+    let mut first_sequence: Vec<usize> = vec![0; template.len()]; // sequence of the first action of each infostate
+    let mut parent_sequence: Vec<Option<usize>> = vec![None; template.len()];
+    let mut team = vec![0usize; template.len()];
+    let mut payoffs: HashMap<(usize, usize), T> = HashMap::new(); // (town sequence, mafia sequence) -> expected town winrate
+    let mut reach: Vec<T> = vec![T::zero(); tree.len()]; // chance probability
+    let mut sequences = vec![[0usize; 2]; tree.len()];
+    reach[0] = T::one();
+    let mut error = None;
+
+    tree.walk(|node, state, actions| {
+        let p = if state.is_day() { 0 } else { 1 };
+        let idx = tree.infostate[node] as usize;
+
+        match parent_sequence[idx] {
+            None => {
+                parent_sequence[idx] = Some(sequences[node][p]);
+                first_sequence[idx] = next_sequence[p];
+                team[idx] = p;
+                next_sequence[p] += actions.len();
+            }
+            Some(parent) => {
+                if parent != sequences[node][p] {
+                    error = Some(LpError::ImperfectRecall(idx));
+                }
+            }
+        }
+
+        for (k, (action, game_action)) in tree.actions(node).zip(actions.iter()).enumerate() {
+            let sum: usize = game_action.transitions.iter().map(|(_, count)| count).sum();
+            for (t, (next_state, count)) in tree.transitions(action).zip(game_action.transitions.iter()) {
+                let to = tree.targets[t] as usize;
+                let prob = reach[node].clone() * T::ratio(*count, sum);
+                let mut next_sequences = sequences[node];
+                next_sequences[p] = first_sequence[idx] + k;
+
+                if next_state.is_terminal() {
+                    let equity = next_state.terminal_equity();
+                    let town_winrate = if next_state.is_day() { equity } else { 1.0 - equity };
+                    if town_winrate > 0.0 {
+                        let payoff = payoffs.entry((next_sequences[0], next_sequences[1])).or_insert_with(T::zero);
+                        *payoff = payoff.clone() + prob;
+                    }
+                } else {
+                    reach[to] = prob;
+                    sequences[to] = next_sequences;
+                }
+            }
+        }
+    });
+
+    if let Some(error) = error {
+        return Err(error);
+    }
+
+    // mafia infostates get constraint ids from 1 (0 is the root)
+    let mut mafia_id = vec![0usize; template.len()];
+    let mut mafia_infostates = 0;
+    let mut owner = vec![0usize; next_sequence[1]]; // mafia sequence -> mafia infostate it belongs to
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); next_sequence[1]]; // mafia sequence -> infostates right after it
+    for idx in 0..template.len() {
+        if parent_sequence[idx].is_some() && team[idx] == 1 {
+            mafia_infostates += 1;
+            mafia_id[idx] = mafia_infostates;
+            owner[first_sequence[idx]..first_sequence[idx] + template[idx].strategy.len()].fill(mafia_infostates);
+            children[parent_sequence[idx].unwrap()].push(mafia_infostates);
+        }
+    }
+
+    // variables: town realization plan x, then mafia infostate values q = q+ - q- (free variables)
+    let n_town = next_sequence[0];
+    let n_values = mafia_infostates + 1;
+    let positive = |k: usize| n_town + k;
+    let negative = |k: usize| n_town + n_values + k;
+
+    let mut lp = Lp {
+        n_vars: n_town + 2 * n_values,
+        objective: vec![(positive(0), T::one()), (negative(0), -T::one())],
+        rows: Vec::new(),
+    };
+
+    // town: x(empty) = 1, and sum of x over the actions of an infostate equals x of its parent sequence
+    lp.rows.push((vec![(0, T::one())], true, T::one()));
+    for idx in 0..template.len() {
+        if let (Some(parent), 0) = (parent_sequence[idx], team[idx]) {
+            let mut row: Vec<(usize, T)> = (first_sequence[idx]..first_sequence[idx] + template[idx].strategy.len())
+                .map(|s| (s, T::one()))
+                .collect();
+            row.push((parent, -T::one()));
+            lp.rows.push((row, true, T::zero()));
+        }
+    }
+
+    // mafia (dual of its realization plan constraints): for every mafia sequence,
+    // value of its infostate minus values of the infostates following it is at most the town's payoff
+    let mut payoff_columns: Vec<Vec<(usize, T)>> = vec![Vec::new(); next_sequence[1]];
+    for ((town, mafia), payoff) in payoffs {
+        payoff_columns[mafia].push((town, payoff));
+    }
+    let first_mafia_row = lp.rows.len();
+    for (s, column) in payoff_columns.into_iter().enumerate() {
+        let mut row = vec![(positive(owner[s]), T::one()), (negative(owner[s]), -T::one())];
+        for &k in &children[s] {
+            row.push((positive(k), -T::one()));
+            row.push((negative(k), T::one()));
+        }
+        for (town, payoff) in column {
+            row.push((town, -payoff));
+        }
+        lp.rows.push((row, false, T::zero()));
+    }
+
+    let solution = lp.solve()?;
+
+    // realization plans are turned into behavioral strategies by normalize(strategy_sum)
+    let mut infostates = template.to_vec();
+    for (idx, infostate) in infostates.iter_mut().enumerate() {
+        if parent_sequence[idx].is_none() {
+            continue;
+        }
+        let plan = if team[idx] == 0 { &solution.primal } else { &solution.dual[first_mafia_row..] };
+        for (a, x) in infostate.strategy_sum.iter_mut().enumerate() {
+            *x = plan[first_sequence[idx] + a].to_f32().unwrap();
+        }
+        infostate.strategy = crate::cfr::normalize(&infostate.strategy_sum);
+    }
+
+    Ok((solution.value, infostates))
+}
@@ -1,617 +1,702 @@
-use std::collections::HashMap;
-use std::collections::VecDeque;
+mod advisor;
+mod cfr;
+mod checkpoint;
+mod deep;
+mod eval;
+mod game;
+mod key;
+mod lp;
+mod mccfr;
+mod policy;
+mod progress;
+#[cfg(test)]
+mod reference;
+mod rollouts;
+mod tree;
+
 use itertools::Itertools;
-use clap::Parser;
-use serde::{Deserialize, Serialize};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use num_rational::BigRational;
+use num_traits::ToPrimitive;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rustc_hash::FxHashMap;
+
+use crate::checkpoint::{Checkpoint, Config, CONFIG, DEEP, INFOSTATES, MCCFR, TREE};
+use crate::advisor::Table;
+use crate::cfr::{evaluate, normalize, perfect_play, update_regrets, Infostate, Pruning, Variant};
+use crate::deep::{DeepCfr, DeepParams};
+use crate::game::{Rules, State};
+use crate::lp::solve_game;
+use crate::mccfr::Mccfr;
+use crate::progress::{Entry, Log, Timings};
+use crate::tree::{compact_actions, Tree};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Solver {
+    /// vanilla CFR over the whole tree
+    Cfr,
+    /// Predictive CFR+ over the whole tree
+    PredictiveCfr,
+    /// external-sampling MCCFR (the tree is only built for evaluation)
+    ExternalSampling,
+    /// outcome-sampling MCCFR, updating along a single sampled game
+    OutcomeSampling,
+    /// Deep CFR, with neural networks in place of regret and strategy tables
+    DeepCfr,
+    /// exact equilibrium from the sequence-form linear program (small games only)
+    Lp,
+}
 
-// todo: pass compilation parameters
-// todo: some assertions
-
-const N_PLAYERS: usize = 7;           // total number of players
-const N_MAFIAS: usize = 2;            // total number of mafias
-const SKIP_FIRST_DAY: bool = true;    // whether to always skip on the first day
-//const n_detectives: usize = 1;      // number of real detectives
-//const n_doctors: usize = 0;         // number of real doctors
-//const n_fake_detectives: usize = 1; // number of mafias pretending to be detectives
-//const n_fake_doctors: usize = 0;    // number of mafias pretending to be doctor
-//const n_bosses: usize = 0;
-
-//const mafias_forced: bool = true;   // whether mafias have to kill during night
-//const save_self: bool = false;      // whether the doctor can save themselves
-//const save_twice: bool = false;     // whether doctor can save themselves twice in a row
-//const talk_killed: bool = false;    // whether night killed can communicate before leaving
-//const reveal_day: bool = false;     // whether roles of day kills are revealed
-//const reveal_night: bool = false;   // whether roles of night kills are revealed
-//const split_votes: bool = false;    // whether more than one person can be voted out in a tie
-
-const SKIP: usize = N_PLAYERS;
-
-// struct for storing currently indistinguishable players
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-struct Player {
-    alive: bool,
-    mafia: bool,
-    count: usize
+impl Solver {
+    fn name(self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct State {
-    kills: Vec<usize>,
-    real_requests: Vec<usize>,
-    real_responses: Vec<bool>,
-    fake_requests: Vec<usize>,
-    fake_responses: Vec<bool>,
-    players: Vec<Player>
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Team {
+    Town,
+    Mafia,
 }
 
-impl State {
-    // convert player id to char for printing
-    fn to_char(x: usize) -> char {
-        if x == N_PLAYERS {
-            return '.';
-        } else {
-            return char::from_u32(x as u32 + 48).unwrap();
-        }
-    }
+// solver recorded in checkpoints of a freshly built tree
+const UNTRAINED: &str = "none";
 
-    fn inc(c: char) -> char { char::from_u32(c as u32 + 1).unwrap() }
+// solvers that keep their own strategy representation and are only matched with the tree for evaluation
+enum Sampler {
+    Tabular(Mccfr),
+    Deep(DeepCfr),
+}
 
-    fn players_string(values: &Vec<usize>) -> String {
-        return values.iter().map(|x| Self::to_char(*x)).collect();
-    }
+#[derive(Args, Debug)]
+struct RulesArgs {
+    /// total number of players
+    #[arg(long, default_value_t = 7)]
+    players: usize,
 
-    fn responses_string(values: &Vec<bool>) -> String {
-        return values.iter().map(|x| if *x {'+'} else {'-'}).collect();
-    }
+    /// total number of mafias
+    #[arg(long, default_value_t = 2)]
+    mafias: usize,
 
-    fn is_day(&self) -> bool {
-        self.kills.len() % 2 == 0
-    }
+    /// whether to always skip on the first day
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    skip_first_day: bool,
+}
 
-    fn state_key(&self) -> String {
-        // kills real_requests fake_requests fake_responses
-        // '+' means mafia, '-' means villager
-        let mut s = String::with_capacity(self.kills.len() + self.real_requests.len() + self.fake_requests.len() + self.fake_responses.len() + 3);
-
-        s.push_str(&Self::players_string(&self.kills));
-        s.push(',');
-        s.push_str(&Self::players_string(&self.real_requests));
-        s.push(',');
-        s.push_str(&Self::players_string(&self.fake_requests));
-        s.push(',');
-        s.push_str(&Self::responses_string(&self.fake_responses));
-    
-        return s;
+impl RulesArgs {
+    fn rules(&self) -> Rules {
+        let rules = Rules { n_players: self.players, n_mafias: self.mafias, skip_first_day: self.skip_first_day };
+        rules.validate().unwrap_or_else(|error| fail(&error));
+        rules
     }
+}
 
-    fn infostate_key_town(&self) -> String {
-        // ab = detectives
-        // kills requests1 responses1 requests2 responses2
-        // whichever gives lexicographically minimal key
+#[derive(Args, Debug)]
+struct BuildArgs {
+    #[command(flatten)]
+    rules: RulesArgs,
 
-        let permutation = |swap: bool| {
-            let mut s = String::with_capacity(self.kills.len() + self.real_requests.len() + self.real_responses.len() + self.fake_requests.len() + self.fake_responses.len() + 4);
+    /// checkpoint to write the tree to
+    output: String,
 
-            let mut m = {
-                let mut array = ['?'; N_PLAYERS + 1];
-                array[N_PLAYERS] = '.';
-                array[0] = if swap { 'b' } else { 'a' };
-                array[1] = if swap { 'a' } else { 'b' };
-                array
-            };
+    /// debug check that no two nodes of the built tree have the same state (slow, uses a lot of memory)
+    #[arg(long, default_value_t = false)]
+    verify_tree: bool,
+}
 
-            let mut next_player = '0';
+#[derive(Args, Debug)]
+struct TrainArgs {
+    #[command(flatten)]
+    rules: RulesArgs,
 
-            let mut f = |x: &usize| {
-                if m[*x] == '?' {
-                    m[*x] = next_player;
-                    next_player = Self::inc(next_player);
-                }
-                return m[*x];
-            };
+    /// checkpoint to continue from (a built tree, an exact solution or an earlier run of the same solver)
+    #[arg(long, default_value_t = String::new())]
+    load_checkpoint: String,
 
-            s.push_str(&self.kills.iter().map(&mut f).collect::<String>());
-            s.push(',');
+    /// checkpoint to write at every evaluation and when training stops
+    #[arg(long, default_value_t = String::new())]
+    save_checkpoint: String,
 
-            let (requests1, responses1, requests2, responses2) = if swap {
-                (&self.real_requests, &self.real_responses, &self.fake_requests, &self.fake_responses)
-            } else {
-                (&self.fake_requests, &self.fake_responses, &self.real_requests, &self.real_responses)
-            };
+    /// number of checkpoints to keep (the older ones get the suffixes .1, .2, ...)
+    #[arg(long, default_value_t = 1)]
+    keep_checkpoints: usize,
 
-            s.push_str(&requests1.into_iter().map(&mut f).collect::<String>());
-            s.push(',');
-            s.push_str(&Self::responses_string(&responses1));
-            s.push(',');
-            s.push_str(&requests2.iter().map(&mut f).collect::<String>());
-            s.push(',');
-            s.push_str(&Self::responses_string(&responses2));
+    /// total number of epochs, including the ones trained before the checkpoint
+    #[arg(long, default_value_t = 10000)]
+    epochs: usize,
 
-            return s;
-        };
+    /// epochs between evaluations of the equilibrium range (0 to never evaluate)
+    #[arg(long, default_value_t = 10)]
+    eval_every: usize,
 
-        return std::cmp::min(permutation(false), permutation(true));
-    }
+    /// stop once the equilibrium range is narrower than this
+    #[arg(long, default_value_t = 0.005)]
+    early_stopping: f32,
 
-    fn infostate_key_mafia(&self) -> String {
-        // a = fake detective
-        // bcd... = mafias
-        // kills requests responses
-        let mut s = String::with_capacity(self.kills.len() + self.fake_requests.len() + self.fake_responses.len() + 2);
-
-        let mut m = {
-            let mut array = ['?'; N_PLAYERS + 1];
-            array[N_PLAYERS] = '.';
-            array[1] = 'a';
-            array
-        };
+    /// stop training after this many seconds (0 for no limit), saving a checkpoint like on Ctrl-C
+    #[arg(long, default_value_t = 0.0)]
+    time_limit: f64,
 
-        let mut next_mafia = 'b';
-        let mut next_villager = '0';
-
-        let mut f = |x: &usize| {
-            if m[*x] == '?' {
-                if self.players[*x].mafia {
-                    m[*x] = next_mafia;
-                    next_mafia = Self::inc(next_mafia);
-                } else {
-                    m[*x] = next_villager;
-                    next_villager = Self::inc(next_villager);
-                }
-            }
-            return m[*x];
-        };
+    /// append a JSON line with the epoch, the equilibrium range, timing, memory use and node counts at every evaluation
+    #[arg(long, default_value_t = String::new())]
+    log: String,
 
-        s.push_str(&self.kills.iter().map(&mut f).collect::<String>());
-        s.push(',');
-        s.push_str(&self.fake_requests.iter().map(&mut f).collect::<String>());
-        s.push(',');
-        s.push_str(&Self::responses_string(&self.fake_responses));
-        
-        return s;
-    }
+    /// solver to train with
+    #[arg(long, value_enum, default_value_t = Solver::Cfr)]
+    solver: Solver,
 
-    fn infostate_key(&self) -> String {
-        if self.is_day() {
-            return self.infostate_key_town();
-        } else {
-            return self.infostate_key_mafia();
-        }
-    }
+    /// sampled iterations per epoch for sampling solvers
+    #[arg(long, default_value_t = 1000)]
+    samples: usize,
 
-    // return a new state where a player was possibly assigned a number
-    // all extra copies are reserved a new number
-    fn touch(&self, player_id: usize) -> State {
-        let mut t = self.clone();
-        if player_id != SKIP {
-            let player = self.players[player_id];
-            if player.count > 1 {
-                t.players.push(Player {
-                    alive: true,
-                    mafia: player.mafia,
-                    count: player.count - 1
-                });
-                t.players[player_id].count = 1;
-            }
-        }
+    /// random seed for sampling solvers
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
 
-        return t;
-    }
+    /// probability of a uniformly random action in outcome sampling
+    #[arg(long, default_value_t = 0.6)]
+    exploration: f32,
 
-    // list of people for mafia to kill
-    fn kill_candidates(&self) -> Vec<(usize, usize)> {
-        let mut results = Vec::new();
+    /// width of the two hidden layers of the Deep CFR networks
+    #[arg(long, default_value_t = 64)]
+    hidden: usize,
 
-        for (i, player) in self.players.iter().enumerate() {
-            if player.alive {
-                results.push((i, player.count));
-            }
-        }
+    /// Deep CFR training steps per fit
+    #[arg(long, default_value_t = 1000)]
+    train_steps: usize,
 
-        if !self.is_day() && self.alive_mafias() == 1 { // never kill self if last mafia
-            results = results.into_iter().filter(|(i, _)| !self.players[*i].mafia).collect();
-        }
+    /// Deep CFR batch size
+    #[arg(long, default_value_t = 256)]
+    batch_size: usize,
 
-        return results;
-    }
+    /// Deep CFR learning rate (Adam)
+    #[arg(long, default_value_t = 0.001)]
+    learning_rate: f32,
 
-    // list of people to vote out (or skip)
-    fn vote_candidates(&self) -> Vec<(usize, usize)> {
-        // todo: never/always kill if detectives in agreement?
-        // todo: detective reporting more than mafias (including other detective)
-        // todo: maybe skip on all even?
-        if self.alive_total() == 4 || (SKIP_FIRST_DAY && self.kills.is_empty()) { // always skip on 4
-            return vec![(SKIP, 1)];
-        }
-        let mut results = self.kill_candidates();
-        if self.alive_total() > 3 {
-            results.push((SKIP, 1));
-        }
-        // todo: on 3, if both "detectives" are alive, we need to kill one of them
-        // also kill either detective or their checked mafia
-        return results;
-    }
+    /// capacity of each Deep CFR sample memory
+    #[arg(long, default_value_t = 1000000)]
+    memory: usize,
 
-    fn check_candidates(&self, detective: usize, requests: &Vec<usize>) -> Vec<(usize, usize)> {
-        // candidate must be alive, not checked, and not self
-        if !self.players[detective].alive || self.alive_total() == 2 { // doesn't matter on 3
-            return vec![(SKIP, 1)]; // skip might mean detective is dead or no valid candidates
-        }
+    /// regret-based pruning (tabular solvers): skip subtrees of the opponent's actions with zero probability and,
+    /// with --solver cfr, own actions whose regret is below -threshold times the reach of their infostate,
+    /// with a full pass every prune_recheck epochs
+    #[arg(long, default_value_t = false)]
+    prune: bool,
 
-        let mut results = Vec::new();
+    /// regret threshold of --prune, relative to the reach of the infostate
+    #[arg(long, default_value_t = 20.0)]
+    prune_threshold: f32,
 
-        for (i, player) in self.players.iter().enumerate() {
-            if player.alive && !requests.contains(&i) && i != detective {
-                results.push((i, player.count));
-            }
-        }
+    /// epochs between full passes of --prune
+    #[arg(long, default_value_t = 10)]
+    prune_recheck: usize,
 
-        if results.is_empty() {
-            results.push((SKIP, 1));
-        }
+    /// solve the linear program in floating point instead of rational arithmetic
+    #[arg(long, default_value_t = false)]
+    float: bool,
+}
 
-        return results;
-    }
+#[derive(Args, Debug)]
+struct EvalArgs {
+    /// checkpoint to evaluate
+    checkpoint: String,
 
-    fn alive_total(&self) -> usize {
-        self.players.iter().map(|player| if player.alive { player.count } else { 0 }).sum::<usize>()
-    }
+    /// write the best response action at every infostate to a CSV file
+    #[arg(long, default_value_t = String::new())]
+    best_responses: String,
 
-    fn alive_mafias(&self) -> usize {
-        self.players.iter().map(|player| if player.alive && player.mafia { player.count } else { 0 }).sum()
-    }
+    /// evaluate a policy table (in the export format) against the checkpoint and against best responses
+    #[arg(long, default_value_t = String::new())]
+    policy: String,
+}
 
-    fn is_terminal(&self) -> bool {
-        self.alive_total() - if self.is_day() { 0usize } else { 1usize } <= 2 * self.alive_mafias() || self.alive_mafias() == 0
-    }
+#[derive(Args, Debug)]
+struct ExploreArgs {
+    /// trained checkpoint
+    checkpoint: String,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-struct Node {
-    state: State,
-    infostate: usize,
-    actions: Vec< Vec<(usize, f32)> >, // possible transition nodes for each infostate action
-    equity: f32, // win probability for the current team
-    frequency: f32, // how often we're in this state
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// trained checkpoint
+    checkpoint: String,
+
+    /// policy table to write (CSV if the name ends with .csv, JSON otherwise)
+    output: String,
+
+    /// only export infostates reached at least this often when both teams follow the policy
+    #[arg(long, default_value_t = 0.0)]
+    min_reach: f32,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-struct Infostate {
-    strategy: Vec<f32>, // current strategy
-    strategy_sum: Vec<f32>, // average strategy (converges to Nash equilibrium)
-    regret_sum: Vec<f32>
+#[derive(Args, Debug)]
+struct SimulateArgs {
+    /// trained checkpoint
+    checkpoint: String,
+
+    /// number of games to play
+    #[arg(long, default_value_t = 10000)]
+    games: usize,
+
+    /// random seed
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// write the first n_sample_rollouts games as readable trajectories (one JSON line per game)
+    #[arg(long, default_value_t = String::new())]
+    sample_rollouts_file: String,
+
+    /// number of games to write to --sample-rollouts-file
+    #[arg(long, default_value_t = 10)]
+    n_sample_rollouts: usize,
 }
 
-fn normalize(regrets: &Vec<f32>) -> Vec<f32> {
-    let mut strategy: Vec<f32> = regrets.iter().map(|&x| if x > 0.0 { x } else { 0.0 }).collect();
-    let sum: f32 = strategy.iter().sum();
-    if sum > 0.0 {
-        strategy = strategy.iter().map(|&x| x / sum).collect();
-    } else {
-        // is this ever called?
-        strategy = vec![1.0 / regrets.len() as f32; regrets.len()];
-    }
-    return strategy;
+#[derive(Args, Debug)]
+struct PlayArgs {
+    /// trained checkpoint
+    checkpoint: String,
+
+    /// team controlled by the human, the checkpoint plays the other one
+    #[arg(long, value_enum, default_value_t = Team::Town)]
+    team: Team,
+
+    /// random by default
+    #[arg(long)]
+    seed: Option<u64>,
 }
 
-// Mafia game solver
-#[derive(Parser, Debug)]
-#[command(version, about)]
-struct Args {
-    #[arg(long, default_value_t = String::new())]
-    load_checkpoint: String,
+#[derive(Args, Debug)]
+struct AdviseArgs {
+    /// trained checkpoint
+    checkpoint: String,
 
-    #[arg(long, default_value_t = String::new())]
-    save_checkpoint: String,
+    /// names of all players, separated by commas
+    #[arg(long, value_delimiter = ',', required = true)]
+    names: Vec<String>,
 
-    #[arg(long, default_value_t = 10000)]
-    epochs: usize,
+    /// the two players claiming to be detectives
+    #[arg(long, value_delimiter = ',', required = true)]
+    claimants: Vec<String>,
 
-    #[arg(long, default_value_t = 10)]
-    eval_every: usize,
+    /// names of the mafias (when advising the mafia instead of the town)
+    #[arg(long, value_delimiter = ',')]
+    mafia: Vec<String>,
+}
 
-    #[arg(long, default_value_t = 0.005)]
-    early_stopping: f32,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// build the game tree and save it as a checkpoint to train from
+    Build(BuildArgs),
+    /// train a strategy from scratch or from a checkpoint
+    Train(TrainArgs),
+    /// exact best responses to the average strategies of a checkpoint and their exploitability
+    Eval(EvalArgs),
+    /// browse the game tree with the average strategies of a checkpoint
+    Explore(ExploreArgs),
+    /// write the average strategies of a checkpoint as a policy table
+    Export(ExportArgs),
+    /// play games with the average strategies of a checkpoint
+    Simulate(SimulateArgs),
+    /// play one team against the average strategies of a checkpoint
+    Play(PlayArgs),
+    /// follow a real game and recommend actions from the average strategies of a checkpoint
+    Advise(AdviseArgs),
+}
 
-    //#[arg(long, default_value_t = String::new())]
-    //sample_rollouts_file: String,
+/// Mafia game solver
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    //#[arg(long, default_value_t = 10)]
-    //n_sample_rollouts: usize,
+// set on the first Ctrl-C, training then stops after the current epoch and saves
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    #[arg(long, default_value_t = false)]
-    explore: bool,
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
 }
 
+// command line settings that change how the solver trains (network sizes are stored with the networks)
+fn settings(args: &TrainArgs) -> Vec<(String, String)> {
+    let settings = match args.solver {
+        Solver::Cfr | Solver::PredictiveCfr => vec![
+            ("prune", args.prune.to_string()),
+            ("prune-threshold", args.prune_threshold.to_string()),
+            ("prune-recheck", args.prune_recheck.to_string()),
+        ],
+        Solver::ExternalSampling | Solver::DeepCfr => vec![("samples", args.samples.to_string())],
+        Solver::OutcomeSampling => vec![("samples", args.samples.to_string()), ("exploration", args.exploration.to_string())],
+        Solver::Lp => vec![("float", args.float.to_string())],
+    };
+    settings.into_iter().map(|(name, value)| (name.to_string(), value)).collect()
+}
 
-fn main() {
-    let args = Args::parse();
+// a checkpoint can only be trained further under the same rules and by the same solver
+// (tabular solvers can also start from a built tree or an exact solution)
+fn validate(config: &Config, args: &TrainArgs, rules: Rules) {
+    if config.rules != rules {
+        let Rules { n_players, n_mafias, skip_first_day } = config.rules;
+        fail(&format!("The checkpoint is for another game, pass --players {} --mafias {} --skip-first-day {}", n_players, n_mafias, skip_first_day));
+    }
+    let tabular = matches!(args.solver, Solver::Cfr | Solver::PredictiveCfr | Solver::Lp);
+    if config.solver == UNTRAINED && !tabular {
+        fail("Sampling solvers don't train from a built tree, start without --load-checkpoint");
+    }
+    let fresh = config.solver == UNTRAINED || (config.solver == Solver::Lp.name() && tabular && args.solver != Solver::Lp);
+    if config.solver != args.solver.name() && !fresh {
+        fail(&format!("The checkpoint was trained with another solver, pass --solver {}", config.solver));
+    }
+    if !fresh {
+        for ((name, value), (_, current)) in config.settings.iter().zip(settings(args)) {
+            if *value != current {
+                println!("Warning: the checkpoint was trained with --{} {}", name, value);
+            }
+        }
+    }
+}
 
-    println!("{}", args.explore);
+// sampling solvers store their own tables, tabular ones store the tree with its infostates
+fn save_checkpoint(path: &str, keep: usize, config: &Config, sampler: &Option<Sampler>, tree: &Tree, infostates: &[Infostate]) {
+    println!("Saving checkpoint to {}", path);
+    let result = checkpoint::Writer::create(path, keep).and_then(|mut writer| {
+        writer.section(CONFIG, config)?;
+        match sampler {
+            Some(Sampler::Tabular(mccfr)) => writer.section(MCCFR, mccfr)?,
+            Some(Sampler::Deep(deep)) => writer.section(DEEP, deep)?,
+            None => {
+                writer.section(TREE, tree)?;
+                writer.section(INFOSTATES, infostates)?;
+            }
+        }
+        writer.finish()
+    });
+    if let Err(error) = result {
+        fail(&format!("Failed to save checkpoint {}: {}", path, error));
+    }
+}
 
-    println!("players = {}, mafias = {}", N_PLAYERS, N_MAFIAS);
+// the tree of any checkpoint with its average strategies (sampling solvers are matched with a newly built tree)
+fn load_strategy(path: &str) -> (Config, Tree, Vec<Infostate>) {
+    println!("Loading checkpoint from {}", path);
+    let (config, checkpoint) = checkpoint::load(path).unwrap_or_else(|error| fail(&format!("Failed to load checkpoint {}: {}", path, error)));
+    let Rules { n_players, n_mafias, skip_first_day } = config.rules;
+    println!("players = {}, mafias = {}, skip first day = {}", n_players, n_mafias, skip_first_day);
+    println!("Trained for {} epochs with --solver {}", config.epoch, config.solver);
+
+    let (tree, infostates) = match checkpoint {
+        Checkpoint::Tabular(tree, infostates) => (tree, infostates),
+        Checkpoint::Sampled(mccfr) => {
+            println!("Building game graph...");
+            let (tree, infostates, index) = Tree::build_indexed(config.rules);
+            let infostates = mccfr.project(&index, &infostates);
+            (tree, infostates)
+        }
+        Checkpoint::Deep(deep) => {
+            println!("Building game graph...");
+            let (tree, infostates) = Tree::build(config.rules);
+            let infostates = deep.tabulate(&tree, &infostates);
+            (tree, infostates)
+        }
+    };
+    (config, tree, infostates)
+}
 
-    let mut nodes: Vec<Node> = Vec::new(); // game tree
-    let mut infostates: Vec<Infostate> = Vec::new(); // infostates and CFR stuff
-    let levels: Vec< Vec<(usize, Vec<usize>)> >; // ordering of non-terminal nodes used while training
+fn build(args: &BuildArgs) {
+    let rules = args.rules.rules();
+    println!("players = {}, mafias = {}", rules.n_players, rules.n_mafias);
 
-    if !args.load_checkpoint.is_empty() {
-        println!("Loading checkpoint from {}", args.load_checkpoint);
-        let data = std::fs::read_to_string(&args.load_checkpoint).unwrap();
-        (nodes, infostates, levels) = serde_json::from_str(&data).unwrap();
-    } else {
-        println!("Building game graph...");
-
-        let initial_state = State {
-            kills: vec![],
-            real_requests: vec![],
-            real_responses: vec![],
-            fake_requests: vec![],
-            fake_responses: vec![],
-            players: if N_MAFIAS == 1 {
-                vec![
-                    Player {alive: true, mafia: false, count: 1},
-                    Player {alive: true, mafia: true, count: 1},
-                    Player {alive: true, mafia: false, count: N_PLAYERS - 2}
-                ]
-            } else {
-                vec![
-                    Player {alive: true, mafia: false, count: 1},
-                    Player {alive: true, mafia: true, count: 1},
-                    Player {alive: true, mafia: true, count: N_MAFIAS - 1},
-                    Player {alive: true, mafia: false, count: N_PLAYERS - N_MAFIAS - 1}
-                ]
+    println!("Building game graph...");
+    let (tree, infostates) = Tree::build(rules);
+    if args.verify_tree {
+        println!("Verifying game graph...");
+        tree.verify_states();
+    }
+
+    println!("States: {}", tree.len()); // including terminal
+    println!("Infostates: {}", infostates.len());
+
+    let config = Config { rules, solver: UNTRAINED.to_string(), settings: Vec::new(), epoch: 0, exploitability: None };
+    save_checkpoint(&args.output, 1, &config, &None, &tree, &infostates);
+}
+
+fn eval(args: &EvalArgs) {
+    let (_, mut tree, infostates) = load_strategy(&args.checkpoint);
+
+    let strategies = eval::strategies(&infostates);
+    let value = eval::value(&tree, &strategies);
+    let town = eval::best_response(&tree, &strategies, 0);
+    let mafia = eval::best_response(&tree, &strategies, 1);
+    println!("Town winrate: {:.6}", value);
+    println!("Town best response: {:.6} (mafia exploitability {:.6})", town.value, town.value - value);
+    println!("Mafia best response: {:.6} (town exploitability {:.6})", mafia.value, value - mafia.value);
+    println!("Equilibrium range: ({:.6}, {:.6})", mafia.value, town.value);
+
+    if !args.best_responses.is_empty() {
+        println!("Writing best responses to {}", args.best_responses);
+        let mut rows = Vec::new();
+        let mut done = vec![false; infostates.len()];
+        tree.walk(|node, state, actions| {
+            let idx = tree.infostate[node] as usize;
+            if !done[idx] {
+                done[idx] = true;
+                let team = if state.is_day() { "town" } else { "mafia" };
+                let best = if state.is_day() { town.actions[idx] } else { mafia.actions[idx] }.unwrap();
+                rows.push(format!("\"{}\",{},\"{}\",{}", state.infostate_key(), team, actions[best].infostate, strategies[idx][best]));
             }
+        });
+        rows.sort();
+        let data = format!("infostate,team,action,probability\n{}\n", rows.join("\n"));
+        if let Err(error) = std::fs::write(&args.best_responses, data) {
+            fail(&format!("Failed to write {}: {}", args.best_responses, error));
+        }
+    }
+
+    if !args.policy.is_empty() {
+        println!("Importing policy from {}", args.policy);
+        let entries = if args.policy.ends_with(".csv") {
+            policy::read_csv(&args.policy)
+        } else {
+            policy::read_json(&args.policy)
         };
+        let imported = entries.and_then(|entries| policy::import(&tree, &infostates, &entries))
+            .unwrap_or_else(|error| fail(&format!("Failed to import policy from {}: {}", args.policy, error)));
+        println!("Infostates missing from the policy (played uniformly): {}", imported.missing);
+        println!("Policy entries that aren't infostates of the game: {}", imported.unused);
+
+        // town winrates, with the checkpoint standing in for the equilibrium
+        let policy = &imported.infostates;
+        let (town_policy, mafia_policy) = (policy::combine(&tree, policy, &infostates), policy::combine(&tree, &infostates, policy));
+        println!("Policy against itself: {:.4}", evaluate(&mut tree, policy));
+        println!("Town policy against the checkpoint: {:.4}", evaluate(&mut tree, &town_policy));
+        println!("Mafia policy against the checkpoint: {:.4}", evaluate(&mut tree, &mafia_policy));
+        println!("Town policy against a best response: {:.4}", perfect_play(&mut tree, policy, 1));
+        println!("Mafia policy against a best response: {:.4}", perfect_play(&mut tree, policy, 0));
+    }
+}
 
-        let mut queue = VecDeque::from([initial_state.clone()]);
-        let mut map_states = HashMap::from([(initial_state.state_key(), 0usize)]); // todo: we can get rid of it because the game is a tree now
-        let mut map_infostates = HashMap::new();
+fn export(args: &ExportArgs) {
+    let (_, tree, infostates) = load_strategy(&args.checkpoint);
 
-        let mut last_level = 0;
-        
-        while !queue.is_empty() {
-            let s = queue.pop_back().unwrap();
+    println!("Exporting policy to {}", args.output);
+    let entries = policy::policy(&tree, &infostates, args.min_reach);
+    let result = if args.output.ends_with(".csv") {
+        policy::write_csv(&args.output, &entries)
+    } else {
+        policy::write_json(&args.output, &entries)
+    };
+    if let Err(error) = result {
+        fail(&format!("Failed to export policy to {}: {}", args.output, error));
+    }
+    println!("Exported {} infostates", entries.len());
+}
 
-            if s.kills.len() > last_level {
-                last_level += 1;
-                println!("level {}, {} nodes", last_level, nodes.len());
-            }
+fn simulate(args: &SimulateArgs) {
+    let (_, tree, infostates) = load_strategy(&args.checkpoint);
+
+    let strategies = eval::strategies(&infostates);
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let (mut total, mut total_squares) = (0.0, 0.0);
+    let mut rollouts = Vec::new();
+    for i in 0..args.games {
+        let game = eval::sample_game(&tree, &strategies, &mut rng);
+        if !args.sample_rollouts_file.is_empty() && i < args.n_sample_rollouts {
+            rollouts.push(rollouts::rollout(&tree, &strategies, i, &game));
+        }
+        let winrate = eval::town_winrate(&tree, game.terminal);
+        total += winrate;
+        total_squares += winrate * winrate;
+    }
 
-            // todo: if there is only one action, prune?
-
-            if s.is_terminal() {
-                nodes.push(Node {
-                    equity: if (s.alive_mafias() == 0) == s.is_day() { 1.0 } else { 0.0 },
-                    state: s,
-                    infostate: 0,
-                    actions: vec![],
-                    frequency: 0.0,
-                });
-                continue;
-            }
+    // the mean with two standard errors
+    let n = args.games as f64;
+    let mean = total / n;
+    let error = ((total_squares / n - mean * mean).max(0.0) / n).sqrt();
+    println!("Town winrate over {} games: {:.4} ± {:.4}", args.games, mean, 2.0 * error);
+    println!("Exact town winrate: {:.4}", eval::value(&tree, &strategies));
+
+    if !args.sample_rollouts_file.is_empty() {
+        println!("Writing {} games to {}", rollouts.len(), args.sample_rollouts_file);
+        if let Err(error) = rollouts::write_jsonl(&args.sample_rollouts_file, &rollouts) {
+            fail(&format!("Failed to write {}: {}", args.sample_rollouts_file, error));
+        }
+    }
+}
 
-            struct RawAction {
-                infostate: String,
-                to: usize,
-                count: usize
-            }
+fn train(args: &TrainArgs) {
+    let started = Instant::now();
+    let mut timings = Timings::default();
+    let mut log = Log::open(&args.log).unwrap_or_else(|error| fail(&format!("Failed to open log {}: {}", args.log, error)));
 
-            let mut raw_actions: Vec<RawAction> = Vec::new();
+    let rules = args.rules.rules();
+    println!("players = {}, mafias = {}", rules.n_players, rules.n_mafias);
 
-            if s.is_day() {
-                // select vote and checks
-                for (p1, c1) in s.vote_candidates() {
-                    let mut s1 = s.touch(p1);
-                    s1.kills.push(p1);
-                    if p1 != SKIP {
-                        s1.players[p1].alive = false;
-                    }
+    let mut tree = Tree::default(); // game tree
+    let mut infostates: Vec<Infostate> = Vec::new(); // infostates and CFR stuff
+    let mut index = FxHashMap::default(); // infostate keys (only kept for sampling solvers)
+    let mut sampler = None;
 
-                    for (p2, c2) in s1.check_candidates(0, &s1.real_requests) {
-                        let mut s2 = s1.touch(p2);
-                        s2.real_requests.push(p2);
-
-                        for (p3, c3) in s2.check_candidates(1, &s2.fake_requests) {
-                            let mut s3 = s2.touch(p3);
-                            s3.fake_requests.push(p3);
-                            let state_key = s3.state_key();
-                            
-                            if !map_states.contains_key(&state_key) {
-                                queue.push_front(s3.clone());
-                                map_states.insert(state_key.clone(), map_states.len());
-                            }
-
-                            let infostate_key = s3.infostate_key_town();
-                            raw_actions.push(RawAction {
-                                infostate: infostate_key,
-                                to: map_states[&state_key],
-                                count: c1 * c2 * c3
-                            });
-                        }
-                    }
+    let mut start = 0; // epochs already trained
+    let mut exploitability = None;
+    let mut saved = None; // epoch of the last saved checkpoint
+
+    let checkpoint = (!args.load_checkpoint.is_empty()).then(|| {
+        println!("Loading checkpoint from {}", args.load_checkpoint);
+        let (config, checkpoint) = checkpoint::load(&args.load_checkpoint)
+            .unwrap_or_else(|error| fail(&format!("Failed to load checkpoint {}: {}", args.load_checkpoint, error)));
+        validate(&config, args, rules);
+        if config.solver == args.solver.name() {
+            println!("Trained for {} epochs with --solver {}", config.epoch, config.solver);
+            start = config.epoch;
+            exploitability = config.exploitability;
+            saved = Some(config.epoch);
+        }
+        checkpoint
+    });
+    let config = |epoch: usize, exploitability: Option<f32>| Config { rules, solver: args.solver.name(), settings: settings(args), epoch, exploitability };
+
+    if matches!(args.solver, Solver::Cfr | Solver::PredictiveCfr | Solver::Lp) {
+        match checkpoint {
+            Some(Checkpoint::Tabular(loaded_tree, loaded_infostates)) => (tree, infostates) = (loaded_tree, loaded_infostates),
+            Some(_) => fail("The checkpoint was saved by a sampling solver"),
+            None => {
+                println!("Building game graph...");
+                (tree, infostates) = Tree::build(rules);
+            }
+        }
+    } else {
+        sampler = Some(if let Some(checkpoint) = checkpoint {
+            match (checkpoint, args.solver) {
+                (Checkpoint::Deep(mut deep), Solver::DeepCfr) => {
+                    deep.reseed(args.seed, start);
+                    Sampler::Deep(deep)
                 }
-            } else {
-                // select kill and response
-                for (p1, c1) in s.kill_candidates() {
-                    let mut s1 = s.touch(p1);
-                    s1.kills.push(p1);
-                    s1.players[p1].alive = false;
-
-                    // if real detective isn't killed, they should respond
-                    let real_request = *s1.real_requests.last().unwrap();
-                    if s1.players[0].alive && real_request != SKIP {
-                        // ^ remove to allow report on death
-                        s1.real_responses.push(s1.players[real_request].mafia);
-                    }
-                    
-                    let mut process_transition = |t: State| {
-                        let state_key = t.state_key();
-                        if !map_states.contains_key(&state_key) {
-                            queue.push_front(t.clone());
-                            map_states.insert(state_key.clone(), map_states.len());
-                        }
-
-                        let infostate_key = t.infostate_key_mafia();
-                        raw_actions.push(RawAction {
-                            infostate: infostate_key,
-                            to: map_states[&state_key],
-                            count: c1
-                        });
-                    };
-
-                    // if fake detective isn't killed, they should report
-                    let fake_request = *s1.fake_requests.last().unwrap();
-                    if s1.players[1].alive && fake_request != SKIP {
-                        // ^ remove to allow report on death
-                        for response in [false, true] {
-                            // todo: can't report more mafias than there are in the game?
-                            let mut s2 = s1.clone();
-                            s2.fake_responses.push(response);
-                            process_transition(s2);
-                        }
-                    } else {
-                        process_transition(s1);
-                    }
+                (Checkpoint::Sampled(mut mccfr), Solver::ExternalSampling | Solver::OutcomeSampling) => {
+                    mccfr.reseed(args.seed, start);
+                    Sampler::Tabular(mccfr)
                 }
+                _ => fail(&format!("The checkpoint wasn't saved by {:?}", args.solver)),
             }
+        } else if args.solver == Solver::DeepCfr {
+            let params = DeepParams {
+                hidden: args.hidden,
+                train_steps: args.train_steps,
+                batch_size: args.batch_size,
+                learning_rate: args.learning_rate,
+                memory: args.memory,
+            };
+            Sampler::Deep(DeepCfr::new(rules, params, args.seed))
+        } else {
+            Sampler::Tabular(Mccfr::new(rules, args.seed))
+        });
 
-            let mut actions: Vec< Vec<(usize, f32)> > = Vec::new();
-
-            raw_actions.sort_by_key(|action| action.infostate.clone());
-            
-            raw_actions.iter().group_by(|raw_action| raw_action.infostate.clone()).into_iter().for_each(|(_, group)| {
-                // todo: get rid of temporary structures
-                let transition_counts: Vec<(usize, usize)> = group.map(|raw_action| (raw_action.to, raw_action.count)).collect();
-                let sum: usize = transition_counts.iter().map(|(_, count)| count).sum();
-                let transition_probabilities: Vec<(usize, f32)> = transition_counts.iter().map(|(to, count)| (*to, *count as f32 / sum as f32)).collect();
-                actions.push(transition_probabilities);
-            });
-            
-            let infostate_key = s.infostate_key();
-            
-            if !map_infostates.contains_key(&infostate_key) {
-                map_infostates.insert(infostate_key.clone(), map_infostates.len());
-                infostates.push(Infostate {
-                    strategy: vec![1.0 / actions.len() as f32; actions.len()],
-                    strategy_sum: vec![0.0; actions.len()],
-                    regret_sum: vec![0.0; actions.len()]
-                });
-            }
-        
-            nodes.push(Node {
-                state: s,
-                equity: 0.0,
-                frequency: 0.0,
-                infostate: map_infostates[&infostate_key],
-                actions
-            });
+        // the whole tree is only needed to evaluate the strategy
+        if args.eval_every > 0 {
+            println!("Building game graph...");
+            (tree, infostates, index) = Tree::build_indexed(rules);
         }
-
-        nodes[0].frequency = 1.0;
-
-        // we want to sort by length and infostates to split workload and improve cache locality
-        let mut node_indices: Vec<usize> = nodes.iter().enumerate().filter(|(_, node)| !node.actions.is_empty()).map(|(i, _)| i).collect();
-        node_indices.sort_by_key(|&idx| (nodes[idx].state.kills.len(), nodes[idx].infostate));
-        
-        levels = node_indices.iter()
-            .group_by(|&&idx| nodes[idx].state.kills.len())
-            .into_iter()
-            .map(|(_, group_by_level)| {
-            group_by_level.group_by(|&&idx| nodes[idx].infostate)
-                .into_iter()
-                .map(|(infostate, group_by_infostate)| {
-                (infostate, group_by_infostate.into_iter().map(|&idx| idx).collect())
-            }).collect()
-        }).collect();
-
-        // todo: ^ this looks like shit
     }
 
-    println!("States: {}", nodes.len()); // including terminal
+    println!("States: {}", tree.len()); // including terminal
     println!("Infostates: {}", infostates.len());
+    timings.add("setup", started);
 
-    for e in 0..args.epochs {
-        if e % args.eval_every == 0 {
-            println!("Epoch {}", e);
+    if args.solver == Solver::Lp {
+        println!("Solving linear program...");
+        let solving = Instant::now();
+        let solution = if args.float {
+            solve_game::<f64>(&tree, &infostates).map(|(value, infostates)| (value.to_string(), value, infostates))
+        } else {
+            solve_game::<BigRational>(&tree, &infostates)
+                .map(|(value, infostates)| (value.to_string(), value.to_f64().unwrap(), infostates))
+        };
+        let (exact, value, solution) = solution.unwrap_or_else(|error| fail(&format!("Failed to solve the linear program: {}", error)));
+        infostates = solution;
+        println!("Game value: {} ({:.6})", exact, value);
+        timings.add("linear program", solving);
+
+        // the extracted strategies should be unexploitable up to f32 rounding
+        let (min_winrate, max_winrate) = (perfect_play(&mut tree, &infostates, 1), perfect_play(&mut tree, &infostates, 0));
+        println!("Equilibrium range: ({:.4}, {:.4})", min_winrate, max_winrate);
+
+        if !args.save_checkpoint.is_empty() {
+            let saving = Instant::now();
+            save_checkpoint(&args.save_checkpoint, args.keep_checkpoints, &config(0, Some(max_winrate - min_winrate)), &sampler, &tree, &infostates);
+            timings.add("saving", saving);
+            saved = Some(0);
+        }
+    }
 
-            let mut perfect_play = |player: usize| {
-                // calculate winrate for player if the opponent's strategy is fixed
-
-                // calculate counterfactual frequencies for each state
-                // (that is, what is the probability of ending up in this state given our actions)
-                for (i, level) in levels.iter().enumerate() {
-                    // todo: this can be done in parallel
-                    for (infostate_idx, matching_nodes) in level {
-                        let strategy = normalize(&infostates[*infostate_idx].strategy_sum);
-                        for node_idx in matching_nodes {
-                            let actions = nodes[*node_idx].actions.clone();
-                            for (transitions, prob_action) in actions.iter().zip_eq(strategy.iter()) {
-                                for (to, prob_transition) in transitions {
-                                    let counterfactual_prob = if i % 2 == player { 1.0 } else { *prob_action };
-                                    nodes[*to].frequency = nodes[*node_idx].frequency * counterfactual_prob * prob_transition;
-                                }
-                            }
-                        }
-                    }
+    // a second Ctrl-C exits right away (the last saved checkpoint is left intact)
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        println!("Interrupted, stopping after the current epoch (Ctrl-C again to exit now)");
+    }).unwrap_or_else(|error| fail(&format!("Failed to set the Ctrl-C handler: {}", error)));
+
+    // the number of epochs includes the ones trained before the checkpoint
+    let epochs = if args.solver == Solver::Lp { 0 } else { args.epochs };
+    let time_limit = (args.time_limit > 0.0).then(|| Duration::from_secs_f64(args.time_limit));
+    let mut trained = start;
+    let mut last_eval = (start, Duration::ZERO); // epoch and training time at the previous evaluation
+    for e in start..epochs {
+        if args.eval_every > 0 && e % args.eval_every == 0 {
+            println!("Epoch {}", e);
+            let evaluating = Instant::now();
+
+            let mut visited_infostates = None;
+            match &mut sampler {
+                Some(Sampler::Tabular(mccfr)) => {
+                    println!("Visited infostates: {}", mccfr.infostates.len());
+                    visited_infostates = Some(mccfr.infostates.len());
+                    infostates = mccfr.project(&index, &infostates);
                 }
-
-                // calculate utilities for each state
-                for (i, level) in levels.iter().enumerate().rev() {
-                    // todo: this can be done in parallel
-                    for (infostate_idx, matching_nodes) in level {
-                        if i % 2 == player {
-                            // choose perfect response for infostate
-                            let infostate = &infostates[*infostate_idx];
-                            let mut utilities = vec![0f32; infostate.strategy.len()];
-                            for node_idx in matching_nodes {
-                                let actions = nodes[*node_idx].actions.clone();
-                                // node -> actions -> transitions
-                                for (utility, transitions) in utilities.iter_mut().zip_eq(actions.iter()) {
-                                    for (to, prob_transition) in transitions {
-                                        *utility += (1.0 - nodes[*to].equity) * prob_transition * nodes[*node_idx].frequency;
-                                    }
-                                }
-                            }
-
-                            //let best_action = utilities.iter().max_by_key(|x| x).unwrap();
-                            let mut best_action = 0;
-                            for (i, u) in utilities.iter().enumerate() {
-                                if *u > utilities[best_action] {
-                                    best_action = i;
-                                }
-                            }
-
-                            for node_idx in matching_nodes {
-                                nodes[*node_idx].equity = nodes[*node_idx].actions[best_action].iter().map(|(to, prob)| prob * (1.0 - nodes[*to].equity)).sum();
-                            }
-                        } else {
-                            // calculate equity for opponent nodes
-                            let strategy = normalize(&infostates[*infostate_idx].strategy_sum); // todo: this can be saved and reused
-                            for node_idx in matching_nodes {
-                                nodes[*node_idx].equity = 0.0;
-                                let actions = nodes[*node_idx].actions.clone();
-                                for (transitions, prob_action) in actions.iter().zip_eq(strategy.iter()) {
-                                    for (to, prob_transition) in transitions {
-                                        nodes[*node_idx].equity += (1.0 - nodes[*to].equity) * prob_action * prob_transition;
-                                    }
-                                }
-                            }
-                        }    
-                    }
+                Some(Sampler::Deep(deep)) => {
+                    deep.train_policy();
+                    infostates = deep.tabulate(&tree, &infostates);
                 }
+                None => {}
+            }
 
-                return nodes[0].equity;
-            };
-
-            let (min_winrate, max_winrate) = (perfect_play(1), perfect_play(0));
+            let (min_winrate, max_winrate) = (perfect_play(&mut tree, &infostates, 1), perfect_play(&mut tree, &infostates, 0));
             println!("Equilibrium range: ({:.4}, {:.4})", min_winrate, max_winrate);
+            exploitability = Some(max_winrate - min_winrate);
+            timings.add("evaluation", evaluating);
+
+            let training = timings.get("training");
+            let epoch_time = (e > last_eval.0).then(|| (training - last_eval.1).as_secs_f64() / (e - last_eval.0) as f64);
+            if let Some(epoch_time) = epoch_time {
+                println!("Time per epoch: {:.4}s ({:.1}s elapsed)", epoch_time, started.elapsed().as_secs_f64());
+            }
+            last_eval = (e, training);
+            let entry = Entry {
+                epoch: e,
+                min_winrate,
+                max_winrate,
+                exploitability: max_winrate - min_winrate,
+                epoch_time,
+                elapsed: started.elapsed().as_secs_f64(),
+                memory: progress::memory(),
+                states: tree.len(),
+                infostates: infostates.len(),
+                visited_infostates,
+            };
+            if let Err(error) = log.write(&entry) {
+                fail(&format!("Failed to write log {}: {}", args.log, error));
+            }
 
             if !args.save_checkpoint.is_empty() {
-                println!("Saving checkpoint to {}", args.save_checkpoint);
-                let data = (nodes.clone(), infostates.clone(), levels.clone()); // todo: get rid of extra memory
-                std::fs::write(&args.save_checkpoint, serde_json::to_string(&data).unwrap()).unwrap();    
+                let saving = Instant::now();
+                save_checkpoint(&args.save_checkpoint, args.keep_checkpoints, &config(e, exploitability), &sampler, &tree, &infostates);
+                timings.add("saving", saving);
+                saved = Some(e);
             }
 
             if max_winrate - min_winrate < args.early_stopping {
@@ -620,149 +705,265 @@ fn main() {
             }
         }
 
-        let mut update_regrets = |player: usize| {
-            // calculates counterfactual regrets for player and updates them
-
-            // todo: this same as before, maybe combine somehow
-            for (i, level) in levels.iter().enumerate() {
-                // todo: this can be done in parallel
-                for (infostate_idx, matching_nodes) in level {
-                    let strategy = &infostates[*infostate_idx].strategy;
-                    for node_idx in matching_nodes {
-                        let actions = nodes[*node_idx].actions.clone();
-                        for (transitions, prob_action) in actions.iter().zip_eq(strategy.iter()) {
-                            for (to, prob_transition) in transitions {
-                                let counterfactual_prob = if i % 2 == player { 1.0 } else { *prob_action };
-                                nodes[*to].frequency = nodes[*node_idx].frequency * counterfactual_prob * prob_transition;
-                            }
-                        }
+        let training = Instant::now();
+        match &mut sampler {
+            Some(Sampler::Tabular(mccfr)) => {
+                for _ in 0..args.samples {
+                    if args.solver == Solver::OutcomeSampling {
+                        mccfr.iterate_outcome(args.exploration);
+                    } else {
+                        mccfr.iterate_external();
                     }
                 }
             }
-
-            // calculate equities bottom-up and update regrets
-            for (i, level) in levels.iter().enumerate().rev() {
-                // todo: this can be done in parallel
-                for (infostate_idx, matching_nodes) in level {
-                    let infostate = &mut infostates[*infostate_idx];
-
-                    let mut regrets = vec![0f32; infostate.strategy.len()];
-                    
-                    for node_idx in matching_nodes {
-                        nodes[*node_idx].equity = 0.0;
-                        let actions = nodes[*node_idx].actions.clone();
-                        for (regret, (transitions, prob_action)) in regrets.iter_mut().zip_eq(actions.iter().zip_eq(infostate.strategy.iter())) {
-                            for (to, prob_transition) in transitions.iter() {
-                                let winrate = 1.0 - nodes[*to].equity;
-                                nodes[*node_idx].equity += winrate * prob_action * prob_transition;
-                                *regret += winrate * prob_transition * nodes[*node_idx].frequency;
-                            }
-                        }
-                        for regret in regrets.iter_mut() {
-                            *regret -= nodes[*node_idx].equity * nodes[*node_idx].frequency;
-                        }
-                    }
-
-                    //let infostate_frequency: f32 = matching_nodes.iter().map(|node| nodes[*node].frequency).sum();
-                    //let scaling_factor = if infostate_frequency > 1e-6 { 1.0 / infostate_frequency } else { 1e6 };
-
-                    // CFR+
-                    // regrets = regrets.iter().map(|&x| if x > 0.0 { x } else { 0.0 }).collect();
-
-                    // weighting
-                    //regrets = regrets.iter().map(|&x| x * f32::ln(1.0 + e as f32)).collect();
-                    //regrets = regrets.iter().map(|&x| x * 1.2 as f32).collect();
-
-                    if i % 2 == player {
-                        // calculate regrets and add to regret_sum
-                        infostate.regret_sum = infostate.regret_sum.iter()
-                            .zip_eq(regrets.iter())
-                            .map(|(s, r)| s + r) // can add a discount factor here
-                            .collect();
-                    
-                        // update strategy and strategy_sum
-                        infostate.strategy = normalize(&infostate.regret_sum);
-                        infostate.strategy_sum = infostate.strategy_sum.iter()
-                            .zip_eq(infostate.strategy.iter())
-                            .map(|(s, x)| s + x)
-                            .collect();
-                    }
-                }
+            Some(Sampler::Deep(deep)) => deep.iterate(args.samples),
+            None => {
+                let variant = if args.solver == Solver::PredictiveCfr { Variant::PredictiveCfrPlus } else { Variant::Vanilla };
+                let pruning = args.prune.then_some(Pruning { threshold: args.prune_threshold, recheck: args.prune_recheck });
+                update_regrets(&mut tree, &mut infostates, 0, variant, e + 1, pruning);
+                update_regrets(&mut tree, &mut infostates, 1, variant, e + 1, pruning);
             }
-        };
+        }
+        timings.add("training", training);
+        trained = e + 1;
 
-        update_regrets(0);
-        update_regrets(1);
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            println!("Stopped after epoch {}", trained);
+            break;
+        }
+        if time_limit.is_some_and(|limit| started.elapsed() >= limit) {
+            println!("Time limit reached after epoch {}", trained);
+            break;
+        }
     }
 
-    if args.explore {
-        println!("Entered interactive mode");
-        println!("Input \"n, k\" to select action, \"ret\" to revert, \"new\" to start over");
+    if let Some(Sampler::Deep(deep)) = &mut sampler {
+        let fitting = Instant::now();
+        deep.train_policy();
+        timings.add("training", fitting);
+    }
+    // epochs trained since the last evaluation
+    if saved != Some(trained) && !args.save_checkpoint.is_empty() {
+        let saving = Instant::now();
+        save_checkpoint(&args.save_checkpoint, args.keep_checkpoints, &config(trained, exploitability), &sampler, &tree, &infostates);
+        timings.add("saving", saving);
+    }
+    timings.report();
+}
 
-        // commands: n k, new, ret
-        let stdin = io::stdin();
-        let mut stack: Vec<usize> = vec![0];
+fn explore(args: &ExploreArgs) {
+    let (_, mut tree, infostates) = load_strategy(&args.checkpoint);
+    perfect_play(&mut tree, &infostates, 0);
 
-        loop {
-            println!();
+    println!("Entered interactive mode");
+    println!("Input \"n, k\" to select action, \"ret\" to revert, \"new\" to start over");
+
+    // commands: n k, new, ret
+    let stdin = io::stdin();
+    let mut stack: Vec<usize> = vec![0];
 
-            let node_id = stack.last().unwrap();
-            let node = &nodes[*node_id];
-            let strategy = normalize(&infostates[node.infostate].strategy_sum);
+    loop {
+        println!();
 
-            println!("       ID: {}", node_id);
-            println!("    State: {}", node.state.state_key());
-            println!("Infostate: {}", node.state.infostate_key());
-            println!("      Day: {}", node.state.is_day());
-            println!(" Equity: {:.4}", node.equity);
-            //println!(" Terminal: {} ({} {} {})", node.state.is_terminal(), node.state.alive_total(), node.state.alive_mafias(), node.equity);
+        let node_id = *stack.last().unwrap();
+        let state = tree.state(node_id);
+        let strategy = normalize(&infostates[tree.infostate[node_id] as usize].strategy_sum);
 
+        println!("       ID: {}", node_id);
+        println!("    State: {}", state.state_key());
+        println!("Infostate: {}", state.infostate_key());
+        println!("      Day: {}", state.is_day());
+        println!(" Equity: {:.4}", tree.equity[node_id]);
+        //println!(" Terminal: {} ({} {} {})", state.is_terminal(), state.alive_total(), state.alive_mafias(), tree.equity[node_id]);
+
+        println!();
+
+        for (i, player) in state.players.iter().enumerate() {
+            println!(" {} {:?}", i, player);
+        }
+
+        println!();
+
+        if tree.is_terminal(node_id) {
+            println!("Game over");
             println!();
-            
-            for (i, player) in node.state.players.iter().enumerate() {
-                println!(" {} {:?}", i, player);
+        } else {
+            for (i, ((action, game_action), action_prob)) in tree.actions(node_id).zip_eq(compact_actions(&state)).zip_eq(strategy).enumerate() {
+                println!("Action {} ({:.4}): {}", i, action_prob, game_action.infostate);
+                for (j, (t, (next_state, _))) in tree.transitions(action).zip_eq(game_action.transitions).enumerate() {
+                    println!("  {:>2}  {:.4}  {:.4}  {}", j, tree.probs[t],  tree.winrate(node_id, t), next_state.state_key());
+                }
+                println!();
             }
+        }
 
-            println!();
+        let mut buffer = String::new();
+        if stdin.read_line(&mut buffer).unwrap() == 0 {
+            break;
+        }
+        buffer = buffer.trim().to_string();
 
-            if node.state.is_terminal() {
-                println!("Game over");
-                println!();
-            } else {
-                for (i, (action, action_prob)) in node.actions.iter().zip_eq(strategy).enumerate() {
-                    let infostate_key = if node.state.is_day() {
-                        nodes[action[0].0].state.infostate_key_town()
-                    } else {
-                        nodes[action[0].0].state.infostate_key_mafia()
-                    };
+        if buffer == "new" {
+            stack.truncate(1);
+        } else if buffer == "ret" {
+            if stack.len() > 1 {
+                stack.pop();
+            }
+        } else {
+            let (a, b) = buffer.split_once(' ').unwrap();
+            let action_id: usize = a.parse().unwrap();
+            let transition_id: usize = b.parse().unwrap();
+            let action = tree.actions(node_id).nth(action_id).unwrap();
+            let t = tree.transitions(action).nth(transition_id).unwrap();
+            stack.push(tree.targets[t] as usize);
+        }
 
-                    println!("Action {} ({:.4}): {}", i, action_prob, infostate_key);
-                    for (j, (transition, transition_prob)) in action.iter().enumerate() {
-                        println!("  {:>2}  {:.4}  {:.4}  {}", j, transition_prob,  1.0 - nodes[*transition].equity, nodes[*transition].state.state_key());
-                    }
-                    println!();
+        // todo: error handling
+        // todo: list other possible nodes for this infostate
+    }
+}
+
+fn play(args: &PlayArgs) {
+    let (_, tree, infostates) = load_strategy(&args.checkpoint);
+    let strategies = eval::strategies(&infostates);
+    let mut rng = args.seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+    let human = args.team == Team::Mafia; // whether the human moves at night
+    let stdin = io::stdin();
+
+    println!("You play the {:?}, input the number of an action to choose it", args.team);
+
+    let mut decisions = Vec::new(); // node and chosen action
+    let mut node = 0;
+    let mut state = State::initial(tree.rules);
+    while !tree.is_terminal(node) {
+        let mut actions = compact_actions(&state);
+        let action = if tree.day[node] != human {
+            println!();
+            println!("Infostate: {}", state.infostate_key());
+            for (i, action) in actions.iter().enumerate() {
+                println!("Action {}: {}", i, action.infostate);
+            }
+            loop {
+                let mut buffer = String::new();
+                if stdin.read_line(&mut buffer).unwrap() == 0 {
+                    return;
+                }
+                match buffer.trim().parse::<usize>() {
+                    Ok(i) if i < actions.len() => break i,
+                    _ => println!("Input a number from 0 to {}", actions.len() - 1),
                 }
             }
+        } else {
+            mccfr::sample(&mut rng, strategies[tree.infostate[node] as usize].iter().map(|&p| p as f32))
+        };
+        if tree.day[node] != human {
+            decisions.push((node, action));
+        }
+
+        // the outcome is only seen through the next infostate
+        let transitions = tree.transitions(tree.actions(node).start + action);
+        let outcome = mccfr::sample(&mut rng, transitions.clone().map(|t| tree.probs[t]));
+        node = tree.targets[transitions.start + outcome] as usize;
+        state = actions.swap_remove(action).transitions.swap_remove(outcome).0;
+    }
 
-            let mut buffer = String::new();
-            stdin.read_line(&mut buffer).unwrap();
-            buffer = buffer.trim().to_string();
+    let town_won = eval::town_winrate(&tree, node) == 1.0;
+    println!();
+    println!("Game over, {} won", if town_won { "town" } else { "mafia" });
+    println!("State: {}", state.state_key());
+    for (i, player) in state.players.iter().enumerate() {
+        println!(" {} {:?}", i, player);
+    }
+
+    // winrates for the human's team, with both teams following the checkpoint after each decision
+    println!();
+    println!("Equity lost against the checkpoint's strategy:");
+    let values = eval::values(&tree, &strategies);
+    let reach = eval::reach(&tree, &strategies, args.team as usize);
+    let mut total = 0.0;
+    for (node, action) in decisions {
+        let infostate = tree.infostate[node] as usize;
+        let own = |town: f64| if human { 1.0 - town } else { town };
+        let action_values: Vec<f64> = eval::action_values(&tree, &values, &reach, infostate).into_iter().map(own).collect();
+        let expected: f64 = action_values.iter().zip_eq(strategies[infostate].iter()).map(|(v, p)| v * p).sum();
+        let lost = expected - action_values[action];
+        total += lost;
+        let label = &compact_actions(&tree.state(node))[action].infostate;
+        println!("{}: chose {} ({:.4}), checkpoint {:.4}, lost {:.4}", tree.state(node).infostate_key(), label, action_values[action], expected, lost);
+    }
+    println!("Total: {:.4}", total);
+}
 
-            if buffer == "new" {
-                stack.truncate(1);
-            } else if buffer == "ret" {
-                if stack.len() > 1 {
-                    stack.pop();
+fn advise(args: &AdviseArgs) {
+    let (_, tree, infostates) = load_strategy(&args.checkpoint);
+    let mut table = Table::new(tree.rules, &args.names, &args.claimants, &args.mafia).unwrap_or_else(|error| fail(&error));
+    let index = advisor::index(&tree, table.mafia as usize);
+    let stdin = io::stdin();
+
+    println!("Input \"day <voted out or skip> <checks of the claimants>\" and \"night <killed> <reports of the claimants, + or ->\"");
+    println!("(checks and reports in the order the claimants were given, only for the ones still checking), \"undo\" to revert");
+
+    loop {
+        println!();
+        if table.our_turn() {
+            let actions = table.state.actions();
+            match index.get(&table.state.infostate_key()) {
+                _ if actions.len() == 1 => println!("Only one choice: {}", table.describe(&actions[0].transitions[0].0)),
+                None => println!("This situation doesn't come up in the solved game (is the game over?)"),
+                Some(&node) => {
+                    // the tree and the table list the same actions, labeled by the infostates they lead to
+                    let strategy = normalize(&infostates[tree.infostate[node] as usize].strategy_sum);
+                    let labels = compact_actions(&tree.state(node)).into_iter().map(|action| action.infostate);
+                    let mut advice: Vec<(f32, &game::Action)> = labels.zip_eq(strategy)
+                        .map(|(label, p)| (p, actions.iter().find(|action| action.infostate == label).unwrap()))
+                        .collect();
+                    advice.sort_by(|a, b| b.0.total_cmp(&a.0));
+                    println!("Infostate: {}", table.state.infostate_key());
+                    let unplayed = advice.iter().filter(|(p, _)| *p < 0.0005).count();
+                    for (p, action) in advice.into_iter().filter(|(p, _)| *p >= 0.0005) {
+                        // choices the solver doesn't tell apart
+                        let choices: Vec<String> = action.transitions.iter().map(|(next, _)| table.describe(next)).collect();
+                        let more = match choices.len() {
+                            1 => String::new(),
+                            2..=4 => format!(" (or {})", choices[1..].join("; ")),
+                            n => format!(" (or {} and {} more)", choices[1..4].join("; "), n - 4),
+                        };
+                        println!("{:.4}  {}{}", p, choices[0], more);
+                    }
+                    if unplayed > 0 {
+                        println!("({} other actions are never played)", unplayed);
+                    }
                 }
-            } else {
-                let (a, b) = buffer.split_once(' ').unwrap();
-                let action_id: usize = a.parse().unwrap();
-                let transition_id: usize = b.parse().unwrap();
-                stack.push(node.actions[action_id][transition_id].0);
             }
+        } else {
+            println!("Waiting for the {}", if table.state.is_day() { "day" } else { "night" });
+        }
 
-            // todo: error handling
-            // todo: list other possible nodes for this infostate
+        let mut buffer = String::new();
+        if stdin.read_line(&mut buffer).unwrap() == 0 {
+            break;
+        }
+        let tokens: Vec<&str> = buffer.split_whitespace().collect();
+        let result = match tokens.split_first() {
+            Some((&"undo", [])) => if table.undo() { Ok(()) } else { Err("Nothing to undo".to_string()) },
+            Some((&phase, tokens)) if phase == "day" || phase == "night" => table.play(phase, tokens),
+            _ => Err("Unknown command".to_string()),
+        };
+        if let Err(error) = result {
+            println!("{}", error);
         }
     }
 }
+
+fn main() {
+    match Cli::parse().command {
+        Command::Build(args) => build(&args),
+        Command::Train(args) => train(&args),
+        Command::Eval(args) => eval(&args),
+        Command::Explore(args) => explore(&args),
+        Command::Export(args) => export(&args),
+        Command::Simulate(args) => simulate(&args),
+        Command::Play(args) => play(&args),
+        Command::Advise(args) => advise(&args),
+    }
+}
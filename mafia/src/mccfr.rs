@@ -0,0 +1,280 @@
+use std::io::{self, Read, Write};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rustc_hash::FxHashMap;
+
+use crate::cfr::{normalize, Infostate};
+use crate::checkpoint::{CheckpointError, Decode, Encode};
+use crate::game::{Rules, State};
+use crate::key::Key;
+
+// Monte Carlo CFR over lazily discovered infostates (no game tree needed)
+pub struct Mccfr {
+    pub rules: Rules,
+    pub index: FxHashMap<Key, usize>, // infostate key -> position in infostates
+    pub infostates: Vec<Infostate>,
+    rng: StdRng,
+}
+
+// decision made in a played game, with the probability it was sampled with
+pub struct Step {
+    pub state: State,
+    pub action: usize, // position in state.actions()
+    pub probability: f32,
+}
+
+pub struct Trajectory {
+    pub steps: Vec<Step>,
+    pub terminal: State,
+}
+
+// random stream of a run with the given seed resumed at the given epoch (the stream of a fresh run at epoch 0)
+pub fn resumed_rng(seed: u64, epoch: usize) -> StdRng {
+    StdRng::seed_from_u64(seed ^ (epoch as u64).wrapping_mul(0x9e3779b97f4a7c15))
+}
+
+// sample an index from unnormalized weights
+pub fn sample(rng: &mut StdRng, weights: impl Iterator<Item = f32> + Clone) -> usize {
+    let total: f32 = weights.clone().sum();
+    let mut x = rng.gen::<f32>() * total;
+    let mut last = 0;
+    for (i, w) in weights.enumerate() {
+        if w > 0.0 {
+            if x < w {
+                return i;
+            }
+            x -= w;
+            last = i;
+        }
+    }
+    last // rounding errors
+}
+
+impl Mccfr {
+    pub fn new(rules: Rules, seed: u64) -> Mccfr {
+        Mccfr { rules, index: FxHashMap::default(), infostates: Vec::new(), rng: StdRng::seed_from_u64(seed) }
+    }
+
+    // a resumed run continues with the stream of its epoch instead of replaying the samples of epoch 0
+    pub fn reseed(&mut self, seed: u64, epoch: usize) {
+        self.rng = resumed_rng(seed, epoch);
+    }
+
+    fn lookup(&mut self, key: Key, n_actions: impl FnOnce() -> usize) -> usize {
+        let n = self.infostates.len();
+        let idx = *self.index.entry(key).or_insert(n);
+        if idx == n {
+            self.infostates.push(Infostate::new(n_actions()));
+        }
+        idx
+    }
+
+    // one external-sampling iteration for each team
+    pub fn iterate_external(&mut self) {
+        let initial_state = State::initial(self.rules);
+        self.traverse(&initial_state, 0);
+        self.traverse(&initial_state, 1);
+    }
+
+    // returns sampled winrate of player (0 = town, 1 = mafia),
+    // exploring all of player's actions and sampling everything else
+    fn traverse(&mut self, s: &State, player: usize) -> f32 {
+        let team = if s.is_day() { 0 } else { 1 };
+
+        if s.is_terminal() {
+            let equity = s.terminal_equity();
+            return if team == player { equity } else { 1.0 - equity };
+        }
+
+        let actions = s.actions();
+        let idx = self.lookup(s.infostate_key(), || actions.len());
+        let strategy = normalize(&self.infostates[idx].regret_sum);
+
+        if team == player {
+            let mut values = vec![0f32; actions.len()];
+            for (value, action) in values.iter_mut().zip(actions.iter()) {
+                let t = sample(&mut self.rng, action.transitions.iter().map(|(_, count)| *count as f32));
+                *value = self.traverse(&action.transitions[t].0, player);
+            }
+
+            let equity: f32 = values.iter().zip(strategy.iter()).map(|(v, p)| v * p).sum();
+
+            let infostate = &mut self.infostates[idx];
+            for (regret, value) in infostate.regret_sum.iter_mut().zip(values.iter()) {
+                *regret += value - equity;
+            }
+            infostate.strategy = normalize(&infostate.regret_sum);
+
+            equity
+        } else {
+            // the opponent's reach is sampled, so the average strategy is accumulated without weights
+            let infostate = &mut self.infostates[idx];
+            for (s, x) in infostate.strategy_sum.iter_mut().zip(strategy.iter()) {
+                *s += x;
+            }
+            infostate.strategy = strategy;
+
+            let a = sample(&mut self.rng, self.infostates[idx].strategy.iter().copied());
+            let action = &actions[a];
+            let t = sample(&mut self.rng, action.transitions.iter().map(|(_, count)| *count as f32));
+            self.traverse(&action.transitions[t].0, player)
+        }
+    }
+
+    // one outcome-sampling iteration: a single game is sampled with both teams
+    // exploring uniformly with probability `exploration`, and both teams learn from it
+    pub fn iterate_outcome(&mut self, exploration: f32) {
+        let trajectory = self.sample_trajectory(exploration);
+        self.learn(&trajectory);
+    }
+
+    pub fn sample_trajectory(&mut self, exploration: f32) -> Trajectory {
+        let mut s = State::initial(self.rules);
+        let mut steps = Vec::new();
+
+        while !s.is_terminal() {
+            let mut actions = s.actions();
+            let idx = self.lookup(s.infostate_key(), || actions.len());
+            let n = actions.len() as f32;
+            let behavior: Vec<f32> = normalize(&self.infostates[idx].regret_sum)
+                .iter()
+                .map(|p| exploration / n + (1.0 - exploration) * p)
+                .collect();
+
+            let a = sample(&mut self.rng, behavior.iter().copied());
+            let t = sample(&mut self.rng, actions[a].transitions.iter().map(|(_, count)| *count as f32));
+            let next = actions[a].transitions.swap_remove(t).0;
+            steps.push(Step { state: s, action: a, probability: behavior[a] });
+            s = next;
+        }
+
+        Trajectory { steps, terminal: s }
+    }
+
+    // outcome-sampling update of both teams from a single played game
+    // (chance events have to follow the real distribution, since their probabilities cancel out)
+    pub fn learn(&mut self, trajectory: &Trajectory) {
+        // strategies the game was played with, taken before either team's regrets change
+        let path: Vec<(usize, Vec<f32>)> = trajectory.steps.iter()
+            .map(|step| {
+                let idx = self.lookup(step.state.infostate_key(), || step.state.actions().len());
+                (idx, normalize(&self.infostates[idx].regret_sum))
+            })
+            .collect();
+        self.learn_team(trajectory, &path, 0);
+        self.learn_team(trajectory, &path, 1);
+    }
+
+    fn learn_team(&mut self, trajectory: &Trajectory, path: &[(usize, Vec<f32>)], player: usize) {
+        // reach / sampling probabilities before each step
+        let mut reaches = Vec::with_capacity(trajectory.steps.len());
+        let mut reach_opponent = 1.0;
+        let mut reach_sampled = 1.0;
+        for (step, (_, strategy)) in trajectory.steps.iter().zip(path) {
+            reaches.push((reach_opponent, reach_sampled));
+            if step.state.is_day() != (player == 0) {
+                reach_opponent *= strategy[step.action];
+            }
+            reach_sampled *= step.probability;
+        }
+
+        let equity = trajectory.terminal.terminal_equity();
+        let team = if trajectory.terminal.is_day() { 0 } else { 1 };
+        let utility = (if team == player { equity } else { 1.0 - equity }) / reach_sampled;
+
+        // probability of playing out the rest of the game (excluding chance)
+        let mut tail = 1.0;
+
+        for ((step, (idx, strategy)), &(reach_opponent, reach_sampled)) in trajectory.steps.iter().zip(path).zip(reaches.iter()).rev() {
+            let infostate = &mut self.infostates[*idx];
+
+            if step.state.is_day() == (player == 0) {
+                let weight = utility * reach_opponent;
+                for (a, regret) in infostate.regret_sum.iter_mut().enumerate() {
+                    if a == step.action {
+                        *regret += weight * tail * (1.0 - strategy[step.action]);
+                    } else {
+                        *regret -= weight * tail * strategy[step.action];
+                    }
+                }
+                infostate.strategy = normalize(&infostate.regret_sum);
+            } else {
+                for (s, x) in infostate.strategy_sum.iter_mut().zip(strategy.iter()) {
+                    *s += reach_opponent / reach_sampled * x;
+                }
+            }
+
+            tail *= strategy[step.action];
+        }
+    }
+
+    // infostates aligned with a fully built tree (unvisited ones keep the tree's uniform strategy)
+    pub fn project(&self, tree_index: &FxHashMap<Key, usize>, tree_infostates: &[Infostate]) -> Vec<Infostate> {
+        let mut infostates = tree_infostates.to_vec();
+        for (key, &i) in tree_index {
+            if let Some(&j) = self.index.get(key) {
+                infostates[i] = self.infostates[j].clone();
+            }
+        }
+        infostates
+    }
+}
+
+impl Encode for Mccfr {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+        self.rules.encode(w)?;
+        self.index.len().encode(w)?;
+        self.index.iter().try_for_each(|(key, idx)| key.encode(w).and_then(|_| idx.encode(w)))?;
+        self.infostates.encode(w)
+    }
+}
+
+impl Decode for Mccfr {
+    fn decode(r: &mut impl Read) -> Result<Mccfr, CheckpointError> {
+        let rules = Rules::decode(r)?;
+        let index: Vec<(Key, usize)> = Vec::decode(r)?;
+        Ok(Mccfr { rules, index: index.into_iter().collect(), infostates: Vec::decode(r)?, rng: StdRng::seed_from_u64(0) }) // replaced by reseed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfr::perfect_play;
+    use crate::lp::solve_game;
+    use crate::tree::Tree;
+
+    const RULES: Rules = Rules { n_players: 5, n_mafias: 1, skip_first_day: true };
+
+    // trains in rounds and returns the width of the equilibrium range after each,
+    // checking that the range brackets the game value
+    fn ranges(mccfr: &mut Mccfr, rounds: usize, iterate: impl Fn(&mut Mccfr)) -> Vec<f32> {
+        let (mut tree, infostates, index) = Tree::build_indexed(RULES);
+        let (value, _) = solve_game::<f64>(&tree, &infostates).unwrap();
+        let mut widths = Vec::new();
+        for round in 0..rounds {
+            for _ in 0..(1000 << round) {
+                iterate(mccfr);
+            }
+            let projected = mccfr.project(&index, &infostates);
+            let (min_winrate, max_winrate) = (perfect_play(&mut tree, &projected, 1), perfect_play(&mut tree, &projected, 0));
+            assert!(min_winrate as f64 <= value + 1e-4 && value <= max_winrate as f64 + 1e-4, "({}, {}) vs {}", min_winrate, max_winrate, value);
+            widths.push(max_winrate - min_winrate);
+        }
+        widths
+    }
+
+    #[test]
+    fn external_sampling_narrows_the_range() {
+        let mut mccfr = Mccfr::new(RULES, 0);
+        let widths = ranges(&mut mccfr, 3, Mccfr::iterate_external);
+        assert!(widths[2] < 0.04 && widths[2] < widths[0] / 2.0, "{:?}", widths);
+    }
+
+    #[test]
+    fn outcome_sampling_narrows_the_range() {
+        let mut mccfr = Mccfr::new(RULES, 0);
+        let widths = ranges(&mut mccfr, 6, |mccfr| mccfr.iterate_outcome(0.6));
+        assert!(widths[5] < 0.15 && widths[5] < widths[0] / 4.0, "{:?}", widths);
+    }
+}
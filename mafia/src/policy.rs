@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::cfr::{normalize, Infostate};
+use crate::key::Key;
+use crate::tree::Tree;
+
+// Standalone policy table: the average strategy of every infostate, without the game tree.
+// Tables are exported from solutions and imported back to evaluate policies written elsewhere.
+
+#[derive(Serialize, Deserialize)]
+pub struct PolicyAction {
+    pub action: Key, // the infostate the action leads to, like in the explorer
+    pub probability: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PolicyEntry {
+    pub infostate: Key,
+    #[serde(default)]
+    pub reach: f32, // probability of reaching the infostate when both teams follow the policy
+    pub actions: Vec<PolicyAction>,
+}
+
+// probability of reaching each infostate under the average strategies of both teams
+fn reach(tree: &Tree, strategies: &[Vec<f32>]) -> Vec<f32> {
+    let mut node_reach = vec![0f32; tree.len()];
+    node_reach[0] = 1.0;
+    let mut reach = vec![0f32; strategies.len()];
+    for i in 0..tree.n_levels() {
+        for g in tree.level(i) {
+            let (infostate_idx, matching_nodes) = tree.group(g);
+            for node_idx in matching_nodes {
+                reach[infostate_idx] += node_reach[node_idx];
+                for (action, prob_action) in tree.actions(node_idx).zip(strategies[infostate_idx].iter()) {
+                    for t in tree.transitions(action) {
+                        node_reach[tree.targets[t] as usize] = node_reach[node_idx] * prob_action * tree.probs[t];
+                    }
+                }
+            }
+        }
+    }
+    reach
+}
+
+// infostates reached with probability at least min_reach, sorted by key
+pub fn policy(tree: &Tree, infostates: &[Infostate], min_reach: f32) -> Vec<PolicyEntry> {
+    let strategies: Vec<Vec<f32>> = infostates.iter().map(|infostate| normalize(&infostate.strategy_sum)).collect();
+    let reach = reach(tree, &strategies);
+
+    let mut done = vec![false; infostates.len()];
+    let mut entries = Vec::new();
+    tree.walk(|node, state, actions| {
+        let idx = tree.infostate[node] as usize;
+        if done[idx] || reach[idx] < min_reach {
+            return;
+        }
+        done[idx] = true;
+        entries.push(PolicyEntry {
+            infostate: state.infostate_key(),
+            reach: reach[idx],
+            actions: actions.iter().zip(strategies[idx].iter())
+                .map(|(action, &probability)| PolicyAction { action: action.infostate.clone(), probability })
+                .collect(),
+        });
+    });
+    entries.sort_by(|a, b| a.infostate.cmp(&b.infostate));
+    entries
+}
+
+pub fn write_json(path: &str, entries: &[PolicyEntry]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut w, entries)?;
+    w.flush()
+}
+
+// one row per action; keys contain commas, so they are quoted
+pub fn write_csv(path: &str, entries: &[PolicyEntry]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "infostate,reach,action,probability")?;
+    for entry in entries {
+        for action in &entry.actions {
+            writeln!(w, "\"{}\",{},\"{}\",{}", entry.infostate, entry.reach, action.action, action.probability)?;
+        }
+    }
+    w.flush()
+}
+
+pub fn read_json(path: &str) -> Result<Vec<PolicyEntry>, String> {
+    let file = File::open(path).map_err(|error| error.to_string())?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|error| error.to_string())
+}
+
+// fields separated by commas, which are ignored inside quotes
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+// rows in the format of write_csv, the reach column may be left empty
+pub fn read_csv(path: &str) -> Result<Vec<PolicyEntry>, String> {
+    let file = File::open(path).map_err(|error| error.to_string())?;
+    let mut entries: Vec<PolicyEntry> = Vec::new();
+    let mut index = FxHashMap::default();
+    for (i, line) in BufReader::new(file).lines().enumerate().skip(1) {
+        let line = line.map_err(|error| error.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("line {}: {}", i + 1, message);
+        let [infostate, reach, action, probability]: [String; 4] = csv_fields(&line).try_into().map_err(|_| error("expected 4 fields"))?;
+        let infostate: Key = infostate.parse().map_err(|e: String| error(&e))?;
+        let action = PolicyAction {
+            action: action.parse().map_err(|e: String| error(&e))?,
+            probability: probability.trim().parse().map_err(|_| error("invalid probability"))?,
+        };
+        let reach = if reach.trim().is_empty() { 0.0 } else { reach.trim().parse().map_err(|_| error("invalid reach"))? };
+        let idx = *index.entry(infostate.clone()).or_insert_with(|| {
+            entries.push(PolicyEntry { infostate, reach, actions: Vec::new() });
+            entries.len() - 1
+        });
+        entries[idx].actions.push(action);
+    }
+    Ok(entries)
+}
+
+pub struct Import {
+    pub infostates: Vec<Infostate>, // the imported policy as strategy_sum
+    pub missing: usize, // infostates of the tree missing from the table (played uniformly)
+    pub unused: usize, // entries of the table that aren't infostates of the tree
+}
+
+// strategies of a policy table for the infostates of a tree; actions missing from an entry
+// are never played, and the probabilities of an entry don't have to add up to one
+pub fn import(tree: &Tree, template: &[Infostate], entries: &[PolicyEntry]) -> Result<Import, String> {
+    let table: FxHashMap<Key, &PolicyEntry> = entries.iter().map(|entry| (entry.infostate.clone(), entry)).collect();
+    let mut infostates = template.to_vec();
+    let mut done = vec![false; template.len()];
+    let mut used = 0;
+    let mut error = None;
+    tree.walk(|node, state, actions| {
+        let idx = tree.infostate[node] as usize;
+        if done[idx] {
+            return;
+        }
+        done[idx] = true;
+        let key = state.infostate_key();
+        let mut strategy = vec![1.0; actions.len()];
+        if let Some(entry) = table.get(&key) {
+            used += 1;
+            strategy.fill(0.0);
+            for action in &entry.actions {
+                match actions.iter().position(|a| a.infostate == action.action) {
+                    Some(a) => strategy[a] += action.probability,
+                    None => error = Some(format!("{}: unknown action {}", key, action.action)),
+                }
+            }
+            if strategy.iter().any(|&p| p < 0.0) || strategy.iter().sum::<f32>() <= 0.0 {
+                error = Some(format!("{}: probabilities must be non-negative and not all zero", key));
+            }
+        }
+        infostates[idx].strategy_sum = strategy.clone();
+        infostates[idx].strategy = strategy;
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok(Import { infostates, missing: template.len() - used, unused: table.len() - used }),
+    }
+}
+
+// strategies of the town from one solution and of mafia from another
+pub fn combine(tree: &Tree, town: &[Infostate], mafia: &[Infostate]) -> Vec<Infostate> {
+    let mut infostates = mafia.to_vec();
+    for i in (0..tree.n_levels()).step_by(2) {
+        for g in tree.level(i) {
+            let idx = tree.group(g).0;
+            infostates[idx] = town[idx].clone();
+        }
+    }
+    infostates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfr::evaluate;
+    use crate::game::Rules;
+    use crate::lp::solve_game;
+
+    #[test]
+    fn policy_covers_reached_infostates() {
+        let rules = Rules { n_players: 5, n_mafias: 1, skip_first_day: true };
+        let (tree, infostates) = Tree::build(rules);
+        let (_, solution) = solve_game::<f64>(&tree, &infostates).unwrap();
+
+        let entries = policy(&tree, &solution, 0.0);
+        assert_eq!(entries.len(), infostates.len());
+        for entry in &entries {
+            let total: f32 = entry.actions.iter().map(|action| action.probability).sum();
+            assert!((total - 1.0).abs() < 1e-4, "{}: {}", entry.infostate, total);
+        }
+
+        // the root is always reached
+        let root = entries.iter().find(|entry| entry.infostate == tree.state(0).infostate_key()).unwrap();
+        assert!((root.reach - 1.0).abs() < 1e-6);
+        let reached = policy(&tree, &solution, 1e-3);
+        assert!(reached.len() < entries.len() && reached.iter().all(|entry| entry.reach >= 1e-3));
+    }
+
+    #[test]
+    fn exported_policy_plays_the_same() {
+        let rules = Rules { n_players: 5, n_mafias: 1, skip_first_day: true };
+        let (mut tree, infostates) = Tree::build(rules);
+        let (value, solution) = solve_game::<f64>(&tree, &infostates).unwrap();
+
+        let path = std::env::temp_dir().join(format!("mafia-{}-policy.csv", std::process::id())).to_str().unwrap().to_string();
+        write_csv(&path, &policy(&tree, &solution, 0.0)).unwrap();
+        let entries = read_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let imported = import(&tree, &infostates, &entries).unwrap();
+        assert_eq!((imported.missing, imported.unused), (0, 0));
+        assert!((evaluate(&mut tree, &imported.infostates) as f64 - value).abs() < 1e-4);
+
+        // a uniformly random town does worse against the equilibrium mafia
+        let uniform = combine(&tree, &infostates, &imported.infostates);
+        assert!((evaluate(&mut tree, &uniform) as f64) < value);
+    }
+}
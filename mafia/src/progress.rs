@@ -0,0 +1,120 @@
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+
+// one line of the training log, written at every evaluation
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub epoch: usize,
+    pub min_winrate: f32,
+    pub max_winrate: f32,
+    pub exploitability: f32,
+    pub epoch_time: Option<f64>, // seconds per epoch since the previous evaluation (without evaluating and saving)
+    pub elapsed: f64, // seconds since the command started
+    pub memory: Option<u64>, // resident memory in bytes (Linux only)
+    pub states: usize,
+    pub infostates: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visited_infostates: Option<usize>, // infostates discovered by MCCFR
+}
+
+// JSON-lines log, appended to so that a resumed run continues the same file
+pub struct Log {
+    w: Option<BufWriter<File>>,
+}
+
+impl Log {
+    pub fn open(path: &str) -> io::Result<Log> {
+        if path.is_empty() {
+            return Ok(Log { w: None });
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Log { w: Some(BufWriter::new(file)) })
+    }
+
+    // flushed right away, so the log can be followed while training
+    pub fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        if let Some(w) = &mut self.w {
+            serde_json::to_writer(&mut *w, entry)?;
+            writeln!(w)?;
+            w.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// field of /proc/self/status in bytes
+fn status(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(field))?;
+    let kilobytes: u64 = line[field.len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+pub fn memory() -> Option<u64> {
+    status("VmRSS:")
+}
+
+pub fn peak_memory() -> Option<u64> {
+    status("VmHWM:")
+}
+
+// wall-clock time spent in each phase of a command, in the order the phases first ran
+#[derive(Default)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    // adds the time since start to the phase
+    pub fn add(&mut self, phase: &'static str, start: Instant) {
+        let elapsed = start.elapsed();
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    pub fn get(&self, phase: &str) -> Duration {
+        self.phases.iter().find(|(name, _)| *name == phase).map_or(Duration::ZERO, |(_, total)| *total)
+    }
+
+    pub fn report(&self) {
+        let phases = self.phases.iter().map(|(name, total)| format!("{} {:.1}s", name, total.as_secs_f64()));
+        println!("Time: {}", phases.collect::<Vec<_>>().join(", "));
+        if let Some(peak) = peak_memory() {
+            println!("Peak memory: {} MB", peak / (1 << 20));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_lines_are_appended() {
+        let file = std::env::temp_dir().join(format!("mafia-{}-log", std::process::id())).to_str().unwrap().to_string();
+        for epoch in [10, 20] {
+            let entry = Entry {
+                epoch,
+                min_winrate: 0.4,
+                max_winrate: 0.5,
+                exploitability: 0.1,
+                epoch_time: Some(0.01),
+                elapsed: 1.0,
+                memory: memory(),
+                states: 100,
+                infostates: 10,
+                visited_infostates: None,
+            };
+            Log::open(&file).unwrap().write(&entry).unwrap();
+        }
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&file).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["epoch"], 20);
+        assert!(lines[0].get("visited_infostates").is_none());
+    }
+}
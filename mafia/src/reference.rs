@@ -0,0 +1,283 @@
+use crate::cfr::{perfect_play, update_regrets, Infostate, Pruning, Variant};
+use crate::game::Rules;
+use crate::lp::solve_game;
+use crate::tree::Tree;
+
+// Brute-force reference for tiny games: every reduced pure strategy of the mafia (there are few of them)
+// against pure best responses of the town, added one at a time until the best response to the mafia's
+// equilibrium mixture gains nothing (double oracle). The matrix games are solved with a dense simplex
+// of their own, so nothing is shared with the sequence form or with lp.rs.
+
+// town winrate when every infostate plays a fixed action
+fn play(tree: &Tree, choices: &[usize], node: usize) -> f64 {
+    if tree.is_terminal(node) {
+        let equity = tree.equity[node] as f64;
+        return if tree.day[node] { equity } else { 1.0 - equity };
+    }
+    let action = tree.actions(node).start + choices[tree.infostate[node] as usize];
+    tree.transitions(action)
+        .map(|t| tree.probs[t] as f64 * play(tree, choices, tree.targets[t] as usize))
+        .sum()
+}
+
+// reduced pure strategies of a team: actions are only assigned to infostates it can still reach
+// given its own earlier choices (usize::MAX elsewhere), which keeps the normal form small
+fn pure_strategies(tree: &Tree, n_infostates: usize, player: usize) -> Vec<Vec<usize>> {
+    let mut result = Vec::new();
+    let mut stack = vec![(vec![usize::MAX; n_infostates], vec![0usize])];
+    while let Some((mut choices, mut frontier)) = stack.pop() {
+        loop {
+            let Some(node) = frontier.pop() else {
+                result.push(choices);
+                break;
+            };
+            if tree.is_terminal(node) {
+                continue;
+            }
+            let idx = tree.infostate[node] as usize;
+            let actions = tree.actions(node);
+            if tree.day[node] == (player == 0) {
+                if choices[idx] == usize::MAX {
+                    // branch on the action, revisiting this node in every branch
+                    for a in 1..actions.len() {
+                        let mut branch = choices.clone();
+                        branch[idx] = a;
+                        let mut branch_frontier = frontier.clone();
+                        branch_frontier.push(node);
+                        stack.push((branch, branch_frontier));
+                    }
+                    choices[idx] = 0;
+                }
+                let action = actions.start + choices[idx];
+                frontier.extend(tree.transitions(action).map(|t| tree.targets[t] as usize));
+            } else {
+                for action in actions {
+                    frontier.extend(tree.transitions(action).map(|t| tree.targets[t] as usize));
+                }
+            }
+        }
+    }
+    result
+}
+
+// value of a matrix game for the maximizing row player and the column player's optimal mixture.
+// With payoffs shifted to be positive, the column player solves max sum(y) s.t. A y <= 1, y >= 0 (its
+// value is 1 / (game value)), here with a condensed tableau and Bland's rule against cycling.
+fn solve_matrix_game(payoffs: &[Vec<f64>]) -> (f64, Vec<f64>) {
+    let (m, n) = (payoffs.len(), payoffs[0].len());
+    let shift = 1.0 - payoffs.iter().flatten().fold(0.0, |min: f64, &x| min.min(x));
+    // rows 0..m are the constraints with the right-hand side in column n, row m is the objective
+    let mut t: Vec<Vec<f64>> = payoffs.iter().map(|row| row.iter().map(|x| x + shift).chain([1.0]).collect()).collect();
+    t.push(vec![-1.0; n].into_iter().chain([0.0]).collect());
+    // variables 0..n are y, n..n + m the slacks of the rows
+    let mut basic: Vec<usize> = (n..n + m).collect();
+    let mut nonbasic: Vec<usize> = (0..n).collect();
+
+    const EPS: f64 = 1e-12;
+    while let Some(k) = (0..n).filter(|&j| t[m][j] < -EPS).min_by_key(|&j| nonbasic[j]) {
+        let r = (0..m)
+            .filter(|&i| t[i][k] > EPS)
+            .min_by(|&a, &b| (t[a][n] / t[a][k]).total_cmp(&(t[b][n] / t[b][k])).then(basic[a].cmp(&basic[b])))
+            .expect("bounded, since the payoffs are positive");
+        let p = t[r][k];
+        for i in (0..=m).filter(|&i| i != r) {
+            let factor = t[i][k] / p;
+            for j in (0..=n).filter(|&j| j != k) {
+                t[i][j] -= factor * t[r][j];
+            }
+            t[i][k] = -factor;
+        }
+        for j in (0..=n).filter(|&j| j != k) {
+            t[r][j] /= p;
+        }
+        t[r][k] = 1.0 / p;
+        std::mem::swap(&mut basic[r], &mut nonbasic[k]);
+    }
+
+    let total = t[m][n];
+    let mut mixture = vec![0.0; n];
+    for (i, &var) in basic.iter().enumerate() {
+        if var < n {
+            mixture[var] = t[i][n] / total;
+        }
+    }
+    (1.0 / total - shift, mixture)
+}
+
+// pure best response of the town to a mixture of the mafia's pure strategies, and its winrate
+fn best_response(tree: &Tree, n_infostates: usize, mafia: &[Vec<usize>], mixture: &[f64]) -> (Vec<usize>, f64) {
+    // the equivalent behavior strategy: how often each action is chosen by the pure strategies reaching the infostate
+    let mut behavior: Vec<Vec<f64>> = vec![Vec::new(); n_infostates];
+    for node in (0..tree.len()).filter(|&node| !tree.day[node] && !tree.is_terminal(node)) {
+        let idx = tree.infostate[node] as usize;
+        if behavior[idx].is_empty() {
+            let n_actions = tree.actions(node).len();
+            let weight = |a: Option<usize>| -> f64 {
+                mafia.iter().zip(mixture).filter(|(m, _)| m[idx] != usize::MAX && a.is_none_or(|a| m[idx] == a)).map(|(_, p)| p).sum()
+            };
+            let total = weight(None);
+            behavior[idx] = (0..n_actions).map(|a| if total > 0.0 { weight(Some(a)) / total } else { 1.0 / n_actions as f64 }).collect();
+        }
+    }
+
+    // probability of reaching every node when the town plays towards it
+    let mut reach = vec![0f64; tree.len()];
+    reach[0] = 1.0;
+    for i in 0..tree.n_levels() {
+        for g in tree.level(i) {
+            let (idx, nodes) = tree.group(g);
+            for node in nodes {
+                for (a, action) in tree.actions(node).enumerate() {
+                    let p = if i % 2 == 0 { 1.0 } else { behavior[idx][a] };
+                    for t in tree.transitions(action) {
+                        reach[tree.targets[t] as usize] = reach[node] * p * tree.probs[t] as f64;
+                    }
+                }
+            }
+        }
+    }
+
+    // town winrates bottom-up, choosing the best action of every town infostate
+    let mut value: Vec<f64> = (0..tree.len()).map(|node| if tree.is_terminal(node) { play(tree, &[], node) } else { 0.0 }).collect();
+    let mut choices = vec![usize::MAX; n_infostates];
+    for i in (0..tree.n_levels()).rev() {
+        for g in tree.level(i) {
+            let (idx, nodes) = tree.group(g);
+            let q = |value: &[f64], action: usize| -> f64 {
+                tree.transitions(action).map(|t| tree.probs[t] as f64 * value[tree.targets[t] as usize]).sum()
+            };
+            if i % 2 == 0 {
+                let n_actions = tree.actions(nodes.start).len();
+                let gain = |a: usize| -> f64 { nodes.clone().map(|node| reach[node] * q(&value, tree.actions(node).start + a)).sum() };
+                let best = (0..n_actions).max_by(|&a, &b| gain(a).total_cmp(&gain(b))).unwrap();
+                choices[idx] = best;
+                for node in nodes {
+                    value[node] = q(&value, tree.actions(node).start + best);
+                }
+            } else {
+                for node in nodes {
+                    value[node] = tree.actions(node).zip(&behavior[idx]).map(|(action, p)| p * q(&value, action)).sum();
+                }
+            }
+        }
+    }
+    (choices, value[0])
+}
+
+pub fn brute_force(tree: &Tree, infostates: &[Infostate]) -> f64 {
+    let n = infostates.len();
+    let mafia = pure_strategies(tree, n, 1);
+    let uniform = vec![1.0 / mafia.len() as f64; mafia.len()];
+    let mut town = vec![best_response(tree, n, &mafia, &uniform).0];
+    let mut payoffs: Vec<Vec<f64>> = Vec::new();
+    loop {
+        for t in &town[payoffs.len()..] {
+            let row = mafia.iter().map(|m| {
+                let choices: Vec<usize> = t.iter().zip(m).map(|(&a, &b)| a.min(b)).collect();
+                play(tree, &choices, 0)
+            });
+            payoffs.push(row.collect());
+        }
+        // the town can't do better than its best response, nor worse than its mixture of the strategies so far
+        let (value, mixture) = solve_matrix_game(&payoffs);
+        let (response, best) = best_response(tree, n, &mafia, &mixture);
+        if best <= value + 1e-9 {
+            return value;
+        }
+        town.push(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigInt;
+    use num_rational::BigRational;
+
+    use super::*;
+
+    fn rules(n_players: usize) -> Rules {
+        Rules { n_players, n_mafias: 1, skip_first_day: true }
+    }
+
+    // worst-case winrates of the town for the average strategies, which bracket the game value
+    fn range(tree: &mut Tree, infostates: &[Infostate]) -> (f64, f64) {
+        (perfect_play(tree, infostates, 1) as f64, perfect_play(tree, infostates, 0) as f64)
+    }
+
+    #[test]
+    fn matrix_games_are_solved() {
+        // matching pennies, and a game with value 1/7 where the third column is dominated
+        for (payoffs, value, mixture) in [
+            (vec![vec![1.0, 0.0], vec![0.0, 1.0]], 0.5, vec![0.5, 0.5]),
+            (vec![vec![3.0, -1.0, 4.0], vec![-2.0, 1.0, 2.0]], 1.0 / 7.0, vec![2.0 / 7.0, 5.0 / 7.0, 0.0]),
+        ] {
+            let (solved, solved_mixture) = solve_matrix_game(&payoffs);
+            assert!((solved - value).abs() < 1e-12, "{} != {}", solved, value);
+            assert!(solved_mixture.iter().zip(&mixture).all(|(a, b)| (a - b).abs() < 1e-12), "{:?}", solved_mixture);
+        }
+    }
+
+    #[test]
+    fn lp_matches_brute_force() {
+        for n_players in 3..=5 {
+            let (tree, infostates) = Tree::build(rules(n_players));
+            let reference = brute_force(&tree, &infostates);
+            let (value, _) = solve_game::<f64>(&tree, &infostates).unwrap();
+            assert!((value - reference).abs() < 1e-6, "{} players: {} != {}", n_players, value, reference);
+        }
+    }
+
+    #[test]
+    fn rational_lp_matches_float() {
+        let (tree, infostates) = Tree::build(rules(5));
+        let (exact, _) = solve_game::<BigRational>(&tree, &infostates).unwrap();
+        let (value, _) = solve_game::<f64>(&tree, &infostates).unwrap();
+        assert_eq!(exact, BigRational::new(BigInt::from(20), BigInt::from(23)));
+        assert!((value - 20.0 / 23.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lp_strategies_are_unexploitable() {
+        for n_players in 3..=5 {
+            let (mut tree, infostates) = Tree::build(rules(n_players));
+            let (value, solution) = solve_game::<f64>(&tree, &infostates).unwrap();
+            let (min_winrate, max_winrate) = range(&mut tree, &solution);
+            assert!((min_winrate - value).abs() < 1e-4 && (max_winrate - value).abs() < 1e-4,
+                "{} players: ({}, {}) vs {}", n_players, min_winrate, max_winrate, value);
+        }
+    }
+
+    fn check_cfr(variant: Variant, pruning: Option<Pruning>) {
+        for n_players in 3..=5 {
+            let (mut tree, mut infostates) = Tree::build(rules(n_players));
+            let (value, _) = solve_game::<f64>(&tree, &infostates).unwrap();
+            for e in 0..1000 {
+                if e % 100 == 0 {
+                    // holds for any strategy pair, converged or not
+                    let (min_winrate, max_winrate) = range(&mut tree, &infostates);
+                    assert!(min_winrate <= value + 1e-4 && value <= max_winrate + 1e-4,
+                        "{} players, epoch {}: ({}, {}) vs {}", n_players, e, min_winrate, max_winrate, value);
+                }
+                update_regrets(&mut tree, &mut infostates, 0, variant, e + 1, pruning);
+                update_regrets(&mut tree, &mut infostates, 1, variant, e + 1, pruning);
+            }
+            let (min_winrate, max_winrate) = range(&mut tree, &infostates);
+            assert!(max_winrate - min_winrate < 0.02, "{} players: ({}, {})", n_players, min_winrate, max_winrate);
+        }
+    }
+
+    #[test]
+    fn cfr_brackets_and_converges_to_value() {
+        check_cfr(Variant::Vanilla, None);
+    }
+
+    #[test]
+    fn pruned_cfr_brackets_and_converges_to_value() {
+        check_cfr(Variant::Vanilla, Some(Pruning { threshold: 20.0, recheck: 10 }));
+    }
+
+    #[test]
+    fn predictive_cfr_brackets_and_converges_to_value() {
+        check_cfr(Variant::PredictiveCfrPlus, None);
+    }
+}
@@ -0,0 +1,93 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::eval::Game;
+use crate::key::Key;
+use crate::tree::{compact_actions, Tree};
+use crate::game::State;
+
+// Readable records of sampled games (forced moves are part of the outcomes, like in the explorer)
+
+#[derive(Serialize, Debug)]
+pub struct RolloutStep {
+    pub team: &'static str,
+    pub state: Key, // full state before the decision
+    pub infostate: Key, // what the acting team knows
+    pub action: Key, // labeled by the infostate it leads to
+    pub probability: f32, // of the action under the strategy
+    pub outcome: Key, // state after the action and the chance events that follow it
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rollout {
+    pub game: usize,
+    pub steps: Vec<RolloutStep>,
+    pub winner: &'static str,
+    pub final_state: Key,
+}
+
+fn team(state: &State) -> &'static str {
+    if state.is_day() { "town" } else { "mafia" }
+}
+
+pub fn rollout(tree: &Tree, strategies: &[Vec<f64>], id: usize, game: &Game) -> Rollout {
+    let mut state = State::initial(tree.rules);
+    let mut steps = Vec::with_capacity(game.steps.len());
+    for step in &game.steps {
+        let mut actions = compact_actions(&state);
+        let action = actions.swap_remove(step.action);
+        let next = action.transitions.into_iter().nth(step.outcome).unwrap().0;
+        steps.push(RolloutStep {
+            team: team(&state),
+            state: state.state_key(),
+            infostate: state.infostate_key(),
+            action: action.infostate,
+            probability: strategies[tree.infostate[step.node] as usize][step.action] as f32,
+            outcome: next.state_key(),
+        });
+        state = next;
+    }
+
+    // the team that won is the one to move if it got equity 1
+    let winner = if state.terminal_equity() == 1.0 { team(&state) } else if state.is_day() { "mafia" } else { "town" };
+    Rollout { game: id, steps, winner, final_state: state.state_key() }
+}
+
+// one game per line
+pub fn write_jsonl(path: &str, rollouts: &[Rollout]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    for rollout in rollouts {
+        serde_json::to_writer(&mut w, rollout)?;
+        writeln!(w)?;
+    }
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{sample_game, strategies, town_winrate};
+    use crate::game::Rules;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn rollouts_follow_the_tree() {
+        let rules = Rules { n_players: 5, n_mafias: 1, skip_first_day: true };
+        let (tree, infostates) = Tree::build(rules);
+        let strategies = strategies(&infostates);
+        let mut rng = StdRng::seed_from_u64(0);
+        for id in 0..100 {
+            let game = sample_game(&tree, &strategies, &mut rng);
+            let rollout = rollout(&tree, &strategies, id, &game);
+            assert_eq!(rollout.final_state, tree.state(game.terminal).state_key());
+            for (step, (sampled, next)) in rollout.steps.iter().zip(game.steps.iter().zip(game.steps.iter().skip(1))) {
+                assert_eq!(step.outcome, tree.state(next.node).state_key());
+                assert_eq!(step.team, if tree.day[sampled.node] { "town" } else { "mafia" });
+            }
+            let town_won = town_winrate(&tree, game.terminal) == 1.0;
+            assert_eq!(rollout.winner, if town_won { "town" } else { "mafia" });
+        }
+    }
+}
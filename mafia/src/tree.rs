@@ -0,0 +1,442 @@
+use std::hash::BuildHasher;
+use std::ops::Range;
+use itertools::Itertools;
+use rayon::prelude::*;
+use rustc_hash::{FxBuildHasher, FxHashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::cfr::Infostate;
+use crate::game::{Action, Rules, State};
+use crate::key::Key;
+
+const CHUNK: usize = 1 << 8; // nodes expanded in parallel at a time while building the tree
+
+// contiguous range of nodes sharing an infostate
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Group {
+    pub infostate: u32,
+    pub start: u32,
+    pub end: u32,
+}
+
+// game tree stored as flat arrays (CSR-style):
+// node i has actions node_actions[i]..node_actions[i + 1],
+// action j has transitions action_transitions[j]..action_transitions[j + 1],
+// and transition k leads to targets[k] with probability probs[k]
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Tree {
+    pub node_actions: Vec<u32>,
+    pub action_transitions: Vec<u32>,
+    pub targets: Vec<u32>,
+    pub probs: Vec<f32>,
+    pub infostate: Vec<u32>, // infostate of each node (0 for terminal nodes)
+    pub parent: Vec<u32>, // used to recompute states on demand
+    pub equity: Vec<f32>, // win probability for the current team
+    pub day: Vec<bool>, // whether it's the town's turn (nodes can be followed by the same team after collapsing)
+    pub frequency: Vec<f32>, // how often we're in this state
+
+    // ordering of non-terminal nodes used while training:
+    // nodes are numbered by level and then by infostate, so each infostate is a contiguous group,
+    // and level i consists of groups levels[i]..levels[i + 1]
+    pub groups: Vec<Group>,
+    pub levels: Vec<u32>,
+
+    pub rules: Rules,
+}
+
+impl Tree {
+    pub fn build(rules: Rules) -> (Tree, Vec<Infostate>) {
+        let (tree, infostates, _) = Self::construct(rules, false);
+        (tree, infostates)
+    }
+
+    // also returns the positions of infostate keys, to match the tree with other solvers
+    // (only built on request, since the keys take more memory than the tree at 7+ players)
+    pub fn build_indexed(rules: Rules) -> (Tree, Vec<Infostate>, FxHashMap<Key, usize>) {
+        let (tree, infostates, index) = Self::construct(rules, true);
+        (tree, infostates, index.unwrap())
+    }
+
+    // the tree is built level by level: states are expanded and their keys computed in parallel,
+    // while indices are assigned in the order of nodes within a level, so the result is deterministic.
+    // Forced nodes (with a single action, like a day with 4 players alive) are then collapsed
+    // into their parents' transitions, so levels may be empty and transitions may skip a level
+    fn construct(rules: Rules, indexed: bool) -> (Tree, Vec<Infostate>, Option<FxHashMap<Key, usize>>) {
+        let mut tree = Tree { node_actions: vec![0], action_transitions: vec![0], rules, ..Default::default() };
+        let mut infostates: Vec<Infostate> = Vec::new();
+        let mut depths: Vec<usize> = Vec::new();
+
+        let initial_state = State::initial(rules);
+
+        let mut map_infostates: Option<FxHashMap<Key, usize>> = indexed.then(FxHashMap::default);
+
+        let mut level = vec![(initial_state, 0u32)];
+
+        while !level.is_empty() {
+            let depth = level[0].0.kills.len();
+            if depth > 0 {
+                println!("level {}, {} nodes", depth, depths.len());
+            }
+
+            // keys never repeat between levels (their length grows with depth), so infostates are interned per level
+            let keys: Vec<Option<Key>> = level.par_iter().map(|(s, _)| (!s.is_terminal()).then(|| s.infostate_key())).collect();
+            let (ids, new_keys) = intern(&keys, infostates.len());
+            if let Some(map_infostates) = &mut map_infostates {
+                for key in new_keys {
+                    map_infostates.insert(key, map_infostates.len());
+                }
+            }
+
+            // the game is a tree, so every transition leads to a new node: children are numbered
+            // right after the current level in the order they are discovered (see verify_states)
+            let next_first = depths.len() + level.len();
+            let mut next_level = Vec::new();
+
+            // actions are expanded in parallel chunks and consumed right away to keep memory usage low
+            for (chunk, chunk_ids) in level.chunks(CHUNK).zip(ids.chunks(CHUNK)) {
+                let expanded: Vec<Option<Vec<Action>>> = chunk.par_iter()
+                    .map(|(s, _)| (!s.is_terminal()).then(|| s.actions()))
+                    .collect();
+
+                for ((s, parent), (actions, &id)) in chunk.iter().zip(expanded.into_iter().zip(chunk_ids)) {
+                    let node = depths.len() as u32;
+                    depths.push(depth);
+                    tree.parent.push(*parent);
+                    tree.day.push(s.is_day());
+
+                    let Some(actions) = actions else {
+                        tree.equity.push(s.terminal_equity());
+                        tree.infostate.push(0);
+                        tree.node_actions.push(tree.action_transitions.len() as u32 - 1);
+                        continue;
+                    };
+
+                    if id as usize == infostates.len() {
+                        infostates.push(Infostate::new(actions.len()));
+                    }
+
+                    for action in actions {
+                        let sum: usize = action.transitions.iter().map(|(_, count)| count).sum();
+                        for (t, count) in action.transitions {
+                            tree.targets.push((next_first + next_level.len()) as u32);
+                            next_level.push((t, node));
+                            tree.probs.push(count as f32 / sum as f32);
+                        }
+                        tree.action_transitions.push(tree.targets.len() as u32);
+                    }
+
+                    tree.equity.push(0.0);
+                    tree.infostate.push(id);
+                    tree.node_actions.push(tree.action_transitions.len() as u32 - 1);
+                }
+            }
+
+            level = next_level;
+        }
+
+        // infostates with a single action (other than the root's) are forced and disappear with their nodes
+        let root_infostate = tree.infostate[0] as usize;
+        let mut renamed = vec![u32::MAX; infostates.len()];
+        let mut kept = 0;
+        for (idx, infostate) in infostates.iter().enumerate() {
+            if infostate.strategy.len() > 1 || idx == root_infostate {
+                renamed[idx] = kept;
+                kept += 1;
+            }
+        }
+        let forced = |node: usize| !tree.is_terminal(node) && renamed[tree.infostate[node] as usize] == u32::MAX;
+        infostates = infostates.into_iter().zip(&renamed).filter(|(_, &id)| id != u32::MAX).map(|(infostate, _)| infostate).collect();
+        if let Some(map_infostates) = &mut map_infostates {
+            map_infostates.retain(|_, idx| renamed[*idx] != u32::MAX);
+            map_infostates.values_mut().for_each(|idx| *idx = renamed[*idx] as usize);
+        }
+
+        // we want to sort by length and infostates to split workload and improve cache locality
+        let mut order: Vec<usize> = (0..tree.len()).filter(|&idx| !forced(idx)).collect();
+        order.sort_by_key(|&idx| (depths[idx], tree.is_terminal(idx), tree.infostate[idx]));
+        let mut tree = tree.renumber(&order);
+        for node in 0..tree.len() {
+            if !tree.is_terminal(node) {
+                tree.infostate[node] = renamed[tree.infostate[node] as usize];
+            }
+        }
+        tree.frequency = vec![0.0; tree.len()];
+        tree.frequency[0] = 1.0;
+
+        tree.levels.push(0);
+        for (depth, group_by_level) in &(0..tree.len()).group_by(|&idx| depths[order[idx]]) {
+            // levels where every node was collapsed are kept empty, so level i is still played by team i % 2
+            while tree.levels.len() <= depth {
+                tree.levels.push(tree.groups.len() as u32);
+            }
+            let non_terminal: Vec<usize> = group_by_level.filter(|&idx| !tree.is_terminal(idx)).collect();
+            for (infostate, group) in &non_terminal.into_iter().group_by(|&idx| tree.infostate[idx]) {
+                let group: Vec<usize> = group.collect();
+                tree.groups.push(Group { infostate, start: group[0] as u32, end: *group.last().unwrap() as u32 + 1 });
+            }
+            tree.levels.push(tree.groups.len() as u32);
+        }
+
+        // the last level only has terminal nodes
+        while tree.levels.len() > 1 && tree.levels[tree.levels.len() - 2] == tree.groups.len() as u32 {
+            tree.levels.pop();
+        }
+
+        (tree, infostates, map_infostates)
+    }
+
+    // new tree where node order[i] becomes node i; nodes missing from the order must have a single action
+    // and are collapsed: transitions leading to them are replaced with the transitions of that action
+    fn renumber(&self, order: &[usize]) -> Tree {
+        let mut index = vec![u32::MAX; self.len()];
+        for (new, &old) in order.iter().enumerate() {
+            index[old] = new as u32;
+        }
+
+        let mut tree = Tree { node_actions: vec![0], action_transitions: vec![0], rules: self.rules, ..Default::default() };
+        for &old in order {
+            for action in self.actions(old) {
+                for t in self.transitions(action) {
+                    self.push_transition(&mut tree, &index, self.targets[t] as usize, self.probs[t]);
+                }
+                tree.action_transitions.push(tree.targets.len() as u32);
+            }
+            tree.node_actions.push(tree.action_transitions.len() as u32 - 1);
+            tree.infostate.push(self.infostate[old]);
+            let mut parent = self.parent[old] as usize;
+            while index[parent] == u32::MAX {
+                parent = self.parent[parent] as usize;
+            }
+            tree.parent.push(index[parent]);
+            tree.equity.push(self.equity[old]);
+            tree.day.push(self.day[old]);
+        }
+
+        tree
+    }
+
+    fn push_transition(&self, tree: &mut Tree, index: &[u32], to: usize, prob: f32) {
+        if index[to] != u32::MAX {
+            tree.targets.push(index[to]);
+            tree.probs.push(prob);
+            return;
+        }
+        for t in self.transitions(self.actions(to).start) {
+            self.push_transition(tree, index, self.targets[t] as usize, prob * self.probs[t]);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.infostate.len()
+    }
+
+    pub fn is_terminal(&self, node: usize) -> bool {
+        self.actions(node).is_empty()
+    }
+
+    pub fn actions(&self, node: usize) -> Range<usize> {
+        self.node_actions[node] as usize..self.node_actions[node + 1] as usize
+    }
+
+    pub fn transitions(&self, action: usize) -> Range<usize> {
+        self.action_transitions[action] as usize..self.action_transitions[action + 1] as usize
+    }
+
+    // win probability of the team to move at node after transition t
+    pub fn winrate(&self, node: usize, t: usize) -> f32 {
+        let to = self.targets[t] as usize;
+        if self.day[to] == self.day[node] { self.equity[to] } else { 1.0 - self.equity[to] }
+    }
+
+    pub fn n_levels(&self) -> usize {
+        self.levels.len() - 1
+    }
+
+    pub fn level(&self, i: usize) -> Range<usize> {
+        self.levels[i] as usize..self.levels[i + 1] as usize
+    }
+
+    // infostate of group g and the nodes in it
+    pub fn group(&self, g: usize) -> (usize, Range<usize>) {
+        let group = &self.groups[g];
+        (group.infostate as usize, group.start as usize..group.end as usize)
+    }
+
+    // replay the game along the tree, calling f for every non-terminal node with its state and actions
+    pub fn walk(&self, mut f: impl FnMut(usize, &State, &[Action])) {
+        let mut stack = vec![(0usize, State::initial(self.rules))];
+        while let Some((node, state)) = stack.pop() {
+            if self.is_terminal(node) {
+                continue;
+            }
+            let actions = compact_actions(&state);
+            f(node, &state, &actions);
+            for (action, game_action) in self.actions(node).zip_eq(actions) {
+                for (t, (next_state, _)) in self.transitions(action).zip_eq(game_action.transitions) {
+                    stack.push((self.targets[t] as usize, next_state));
+                }
+            }
+        }
+    }
+
+    // debug check that the game is indeed a tree: no two nodes (reached by different paths)
+    // have the same canonical state, which would otherwise have to be merged
+    pub fn verify_states(&self) {
+        let mut seen = FxHashMap::default();
+        let mut stack = vec![(0usize, State::initial(self.rules))];
+        while let Some((node, state)) = stack.pop() {
+            if let Some(other) = seen.insert(state.state_key(), node) {
+                panic!("nodes {} and {} have the same state {}", other, node, state.state_key());
+            }
+            if self.is_terminal(node) {
+                continue;
+            }
+            for (action, game_action) in self.actions(node).zip_eq(compact_actions(&state)) {
+                for (t, (next_state, _)) in self.transitions(action).zip_eq(game_action.transitions) {
+                    stack.push((self.targets[t] as usize, next_state));
+                }
+            }
+        }
+    }
+
+    // recompute the state of a node by replaying the path to it from the root
+    pub fn state(&self, node: usize) -> State {
+        let mut path = vec![node];
+        while *path.last().unwrap() != 0 {
+            path.push(self.parent[*path.last().unwrap()] as usize);
+        }
+
+        let mut state = State::initial(self.rules);
+        for (&from, &to) in path.iter().rev().tuple_windows() {
+            let actions = self.actions(from);
+            let transitions = self.action_transitions[actions.start] as usize..self.action_transitions[actions.end] as usize;
+            let position = self.targets[transitions]
+                .iter()
+                .position(|&t| t as usize == to)
+                .unwrap();
+            state = compact_actions(&state).into_iter()
+                .flat_map(|action| action.transitions)
+                .nth(position)
+                .unwrap()
+                .0;
+        }
+
+        state
+    }
+}
+
+// actions of a state the way they are stored in the tree: forced states reached by them
+// (non-terminal, with a single action) are replaced by the outcomes of that action,
+// with counts scaled to keep the exact probabilities (compacting doesn't change the number of actions,
+// so only the chains of forced states are followed)
+pub fn compact_actions(state: &State) -> Vec<Action> {
+    let mut actions = state.actions();
+    for action in &mut actions {
+        let outcomes: Vec<Option<Vec<(State, usize)>>> = action.transitions.iter()
+            .map(|(s, _)| (!s.is_terminal() && s.actions().len() == 1).then(|| compact_actions(s).pop().unwrap().transitions))
+            .collect();
+        if outcomes.iter().all(Option::is_none) {
+            continue;
+        }
+
+        let sums: Vec<usize> = outcomes.iter().map(|outcome| outcome.as_ref().map_or(1, |outcome| outcome.iter().map(|(_, count)| count).sum())).collect();
+        let lcm = sums.iter().fold(1, |lcm, &sum| lcm / gcd(lcm, sum) * sum);
+        let mut transitions = Vec::new();
+        for (((s, count), outcome), sum) in action.transitions.drain(..).zip(outcomes).zip(sums) {
+            match outcome {
+                None => transitions.push((s, count * lcm)),
+                Some(outcome) => transitions.extend(outcome.into_iter().map(|(t, c)| (t, count * c * (lcm / sum)))),
+            }
+        }
+        action.transitions = transitions;
+    }
+    actions
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// numbers of keys in the order of their first occurrence, starting from `first` (u32::MAX for None),
+// and the distinct keys in that order; keys are deduplicated in parallel in shards by their hash
+fn intern(keys: &[Option<Key>], first: usize) -> (Vec<u32>, Vec<Key>) {
+    const SHARDS: usize = 64;
+
+    let mut shards: Vec<Vec<usize>> = vec![Vec::new(); SHARDS];
+    for (i, shard) in keys.par_iter().map(|key| key.as_ref().map(|key| FxBuildHasher.hash_one(key) as usize % SHARDS)).collect::<Vec<_>>().into_iter().enumerate() {
+        if let Some(shard) = shard {
+            shards[shard].push(i);
+        }
+    }
+
+    // position of the first occurrence of the key at each position
+    let firsts: Vec<Vec<usize>> = shards.par_iter()
+        .map(|shard| {
+            let mut map = FxHashMap::default();
+            shard.iter().map(|&i| *map.entry(keys[i].as_ref().unwrap()).or_insert(i)).collect()
+        })
+        .collect();
+
+    let mut numbers = vec![u32::MAX; keys.len()];
+    for (shard, firsts) in shards.iter().zip(&firsts) {
+        for (&i, &first) in shard.iter().zip(firsts) {
+            numbers[i] = first as u32;
+        }
+    }
+
+    // first occurrences are numbered in order, and the other occurrences refer to them
+    let mut new_keys = Vec::new();
+    for i in 0..keys.len() {
+        if numbers[i] as usize == i {
+            numbers[i] = (first + new_keys.len()) as u32;
+            new_keys.push(keys[i].clone().unwrap());
+        } else if numbers[i] != u32::MAX {
+            numbers[i] = numbers[numbers[i] as usize];
+        }
+    }
+
+    (numbers, new_keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_is_deterministic() {
+        let rules = Rules { n_players: 6, n_mafias: 2, skip_first_day: true };
+        let build = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let (tree, infostates, index) = pool.install(|| Tree::build_indexed(rules));
+            let index: Vec<(Key, usize)> = index.into_iter().sorted_by_key(|&(_, i)| i).collect();
+            serde_json::to_string(&(tree, infostates, index)).unwrap()
+        };
+        assert!(build(1) == build(4));
+    }
+
+    #[test]
+    fn states_are_replayed_quickly() {
+        // replaying a path only expands the states along it and their children, not the subtrees below them
+        // (~2ms per state, while expanding subtrees took seconds)
+        let rules = Rules { n_players: 7, n_mafias: 2, skip_first_day: true };
+        let (tree, _) = Tree::build(rules);
+        let start = std::time::Instant::now();
+        for node in (0..tree.len()).step_by(tree.len() / 100) {
+            let state = tree.state(node);
+            assert_eq!(state.is_terminal(), tree.is_terminal(node));
+            assert_eq!(state.is_day(), tree.day[node]);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed.as_secs_f64() < 5.0, "{:?} for 100 states", elapsed);
+    }
+
+    #[test]
+    fn states_are_unique() {
+        for rules in [
+            Rules { n_players: 5, n_mafias: 1, skip_first_day: true },
+            Rules { n_players: 6, n_mafias: 2, skip_first_day: false },
+        ] {
+            Tree::build(rules).0.verify_states();
+        }
+    }
+}